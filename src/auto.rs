@@ -0,0 +1,191 @@
+use fastq_pair::{Config, format_size, HeaderKeyExtractor, is_compressed, is_stream, Mate, open_mate, Result};
+use std::collections::HashSet;
+use std::fs;
+use super::duplicate_policy_error;
+
+/// Records read from the start of each input to gauge how far apart mates are
+pub const SAMPLE_SIZE: usize = 10_000;
+
+/// Rough size of plain FASTQ relative to its gzip or BAM compressed form
+const COMPRESSION_RATIO: u64 = 4;
+
+/// Rough bytes of bookkeeping per read or offset held in a hash map: the
+/// slot itself, plus the key's and the read's heap allocations
+const ENTRY_OVERHEAD: u64 = 100;
+
+/// Rough bytes per key remembered as a hash to spot duplicates: the keys
+/// of pairs for "iter" and "seek-iter" with `--remember-paired`. "store"
+/// and "seek" only remember read 2 singletons, which are left out.
+const KEY_HASH_SIZE: u64 = 32;
+
+/// Budget used when available memory can't be read from `/proc/meminfo`
+const DEFAULT_BUDGET: u64 = 4 << 30;
+
+/// What the input sizes and their leading records say about the inputs
+#[derive(Debug)]
+pub struct Sample {
+    /// Estimated number of records of each mate
+    pub records: u64,
+    /// Average size of a record, and of its pairing key, in bytes
+    pub record_size: u64,
+    pub key_size: u64,
+    /// The most reads left waiting for their mate at once while stepping
+    /// through both samples together, as a fraction of the sampled reads
+    pub pending: f64,
+}
+
+/// The method picked and why
+#[derive(Debug)]
+pub struct Choice {
+    pub method: &'static str,
+    pub reason: String,
+}
+
+/// Memory still available to new processes, going by `/proc/meminfo`
+pub fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb << 10)
+}
+
+pub fn default_budget() -> u64 {
+    available_memory().unwrap_or(DEFAULT_BUDGET)
+}
+
+/// Uncompressed size of an input, estimated for compressed ones
+fn plain_size(path: &str) -> Result<u64> {
+    let size = fs::metadata(path)?.len();
+    Ok(if is_compressed(path)? { size * COMPRESSION_RATIO } else { size })
+}
+
+/// Read the first `sample_size` records of both mates in step, the way
+/// "iter_both" would, tracking how many are waiting for their mate
+pub fn sample(r1_path: &str, r2_path: &str, config: &Config, sample_size: usize) -> Result<Sample> {
+    let mut reader1 = open_mate(r1_path, Mate::R1, config)?;
+    let mut reader2 = open_mate(r2_path, Mate::R2, config)?;
+    let key1 = HeaderKeyExtractor::for_input(config, &mut reader1)?;
+    let key2 = HeaderKeyExtractor::for_input(config, &mut reader2)?;
+    let mut inputs = [(reader1, key1), (reader2, key2)];
+    let mut pending = [HashSet::new(), HashSet::new()];
+    let (mut reads, mut record_bytes, mut key_bytes, mut peak) = (0, 0, 0, 0);
+    for _ in 0..sample_size {
+        let mut done = true;
+        for (mate, (reader, extractor)) in inputs.iter_mut().enumerate() {
+            if let Some(read) = reader.next_record()? {
+                let key = extractor.key(read.header)?;
+                reads += 1;
+                record_bytes += read.header.len() + read.seq.len() + read.separator.len() + read.qscore.len() + 4;
+                key_bytes += key.len();
+                if !pending[1 - mate].remove(&key) {
+                    pending[mate].insert(key);
+                }
+                done = false;
+            }
+        }
+        peak = peak.max(pending[0].len() + pending[1].len());
+        if done {
+            break;
+        }
+    }
+    if reads == 0 {
+        return Ok(Sample { records: 0, record_size: 0, key_size: 0, pending: 0.0 });
+    }
+    // Both mates share the input when it's SAM/BAM or interleaved
+    let total = if r1_path == r2_path { plain_size(r1_path)? } else { plain_size(r1_path)? + plain_size(r2_path)? };
+    let record_size = (record_bytes / reads) as u64;
+    Ok(Sample {
+        records: total / 2 / record_size.max(1),
+        record_size,
+        key_size: (key_bytes / reads) as u64,
+        pending: peak as f64 / reads as f64,
+    })
+}
+
+/// Pick the fastest method whose estimated peak memory fits within `budget`.
+/// In order of speed these are "iter", whose memory grows with how far
+/// apart mates are, "store", which holds all of read 1, then the methods
+/// that only hold offsets and finally "partition". Methods that can't deal
+/// with duplicates as `config` asks are passed over.
+pub fn choose(sample: &Sample, budget: u64, buckets: usize, config: &Config) -> Choice {
+    let read = sample.record_size + sample.key_size + ENTRY_OVERHEAD;
+    let offset = sample.key_size + ENTRY_OVERHEAD;
+    let waiting = (sample.pending * 2.0 * sample.records as f64) as u64;
+    let paired = if config.remember_paired { sample.records * KEY_HASH_SIZE } else { 0 };
+    let estimates = [
+        ("iter", waiting * read + paired),
+        ("store", sample.records * read),
+        ("seek-iter", waiting * offset + paired),
+        ("seek", sample.records * offset),
+    ];
+    let shape = format!("{:.1}% of sampled reads were waiting for their mate at once, of ~{} per mate",
+                        sample.pending * 100.0, sample.records);
+    for (method, estimate) in &estimates {
+        if *estimate <= budget && duplicate_policy_error(method, config).is_none() {
+            return Choice {
+                method,
+                reason: format!("{}; {} needs ~{}, within the {} budget",
+                                shape, method, format_size(*estimate), format_size(budget)),
+            };
+        }
+    }
+    Choice {
+        method: "partition",
+        reason: format!("{}; even seek needs ~{}, over the {} budget, so partition holds one of {} buckets at a time",
+                        shape, format_size(estimates[3].1), format_size(budget), buckets),
+    }
+}
+
+/// Sample the inputs and choose a method for them. Streams can't be sampled
+/// without consuming them, so they get "iter", or "store" if the duplicate
+/// policy rules iter out, which read them once.
+pub fn choose_method(r1_path: &str, r2_path: &str, config: &Config, budget: u64, buckets: usize) -> Result<Choice> {
+    if is_stream(r1_path)? || is_stream(r2_path)? {
+        let method = if duplicate_policy_error("iter", config).is_none() { "iter" } else { "store" };
+        return Ok(Choice { method, reason: format!("streamed input can't be sampled; {} reads it once", method) });
+    }
+    Ok(choose(&sample(r1_path, r2_path, config, SAMPLE_SIZE)?, budget, buckets, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::shuffled_inputs;
+    use fastq_pair::OnDuplicate;
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sample() {
+        let config = Config::default();
+        let paired = sample("data/ncbi_1_paired.fastq", "data/ncbi_2_paired.fastq", &config, SAMPLE_SIZE).unwrap();
+        assert_eq!((paired.records, paired.pending), (4, 0.0));
+        assert_eq!(paired.key_size, "@SRR3380692.1".len() as u64);
+        let tmpdir = tempdir().unwrap();
+        let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+        let shuffled = sample(&r1_path, &r2_path, &config, SAMPLE_SIZE).unwrap();
+        assert_eq!(shuffled.records, 5);
+        assert!(shuffled.pending > 0.0);
+        // Only the first record of each is read
+        assert_eq!(sample(&r1_path, &r2_path, &config, 1).unwrap().pending, 1.0);
+    }
+
+    #[test]
+    fn test_choose() {
+        let sample = |pending| Sample { records: 1_000_000, record_size: 400, key_size: 20, pending };
+        let config = Config::default();
+        assert_eq!(choose(&sample(0.01), 1 << 30, 64, &config).method, "iter");
+        assert_eq!(choose(&sample(0.9), 600 << 20, 64, &config).method, "store");
+        assert_eq!(choose(&sample(0.5), 160 << 20, 64, &config).method, "seek-iter");
+        assert_eq!(choose(&sample(0.9), 160 << 20, 64, &config).method, "seek");
+        let choice = choose(&sample(0.9), 10 << 20, 64, &config);
+        assert_eq!(choice.method, "partition");
+        assert!(choice.reason.contains("90.0% of sampled reads"));
+        // Remembering paired keys costs iter room it otherwise has
+        let remember = Config { remember_paired: true, ..Config::default() };
+        assert_eq!(choose(&sample(0.01), 40 << 20, 64, &remember).method, "seek-iter");
+        assert_eq!(choose(&sample(0.01), 40 << 20, 64, &config).method, "iter");
+        // Methods pairing as they go can't keep the last copy of a read
+        let last = Config { on_duplicate: OnDuplicate::Last, ..Config::default() };
+        assert_eq!(choose(&sample(0.01), 1 << 30, 64, &last).method, "store");
+    }
+}
@@ -0,0 +1,124 @@
+use flate2::{Compression, Crc};
+use flate2::write::DeflateEncoder;
+use std::io::{self, Write};
+
+/// Maximum uncompressed bytes per block; matches htslib so a block always fits in 64KB
+const MAX_BLOCK_SIZE: usize = 0xff00;
+
+/// Empty block marking the end of a BGZF file
+pub const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Writes blocked gzip (BGZF) as used by samtools/htslib. Each block is an
+/// independent gzip member, so the output is also readable by plain gzip.
+pub struct Writer<W: Write> {
+    inner: Option<W>,
+    buffer: Vec<u8>,
+    level: Compression,
+}
+
+/// Compress `data` into a single block and write it out
+fn write_block(inner: &mut impl Write, level: Compression, data: &[u8]) -> io::Result<()> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    let mut crc = Crc::new();
+    crc.update(data);
+    // Header (18) + compressed data + CRC32 and ISIZE (8), minus one
+    let block_size = (compressed.len() + 25) as u16;
+    inner.write_all(&[0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff,
+                      0x06, 0x00, b'B', b'C', 0x02, 0x00])?;
+    inner.write_all(&block_size.to_le_bytes())?;
+    inner.write_all(&compressed)?;
+    inner.write_all(&crc.sum().to_le_bytes())?;
+    inner.write_all(&(data.len() as u32).to_le_bytes())
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W, level: Compression) -> Writer<W> {
+        Writer {
+            inner: Some(inner),
+            buffer: Vec::with_capacity(MAX_BLOCK_SIZE),
+            level,
+        }
+    }
+
+    /// Write any buffered data as a (possibly short) block
+    fn write_buffer(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let inner = self.inner.as_mut().expect("BGZF writer already finished");
+            write_block(inner, self.level, &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Write remaining data and the EOF marker, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_buffer()?;
+        let mut inner = self.inner.take().expect("BGZF writer already finished");
+        inner.write_all(&EOF_BLOCK)?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == MAX_BLOCK_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        self.inner.as_mut().expect("BGZF writer already finished").flush()
+    }
+}
+
+impl<W: Write> Drop for Writer<W> {
+    fn drop(&mut self) {
+        // Best effort, like flate2's encoders; call `finish` to see errors
+        if self.inner.is_some() {
+            let _ = self.write_buffer();
+            if let Some(inner) = self.inner.as_mut() {
+                let _ = inner.write_all(&EOF_BLOCK);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = Writer::new(Vec::new(), Compression::default());
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.ends_with(&EOF_BLOCK));
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_block_header() {
+        let mut writer = Writer::new(Vec::new(), Compression::default());
+        writer.write_all(b"@read\nACGT\n+\nIIII\n").unwrap();
+        let compressed = writer.finish().unwrap();
+        // BC extra subfield holds the total block size minus one
+        assert_eq!(&compressed[12..14], b"BC");
+        let block_size = u16::from_le_bytes([compressed[16], compressed[17]]) as usize + 1;
+        assert_eq!(compressed.len(), block_size + EOF_BLOCK.len());
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::{self, BufRead};
+use super::{mate_of, FastqReader, Mate};
+
+/// Streams the records of a single mate from an interleaved FASTQ or FASTA,
+/// where mates alternate but some may be missing after filtering. The mate
+/// of each record is read from its header (`/1`, `.1` or a CASAVA `1:N:...`
+/// comment). Wrapped records are passed on unwrapped.
+pub struct MateStream<R: BufRead> {
+    inner: FastqReader<R>,
+    mate: Mate,
+    record: usize,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> MateStream<R> {
+    /// `path` is only used in error messages
+    pub fn new(inner: R, mate: Mate, path: &str) -> MateStream<R> {
+        MateStream {
+            inner: FastqReader::new(inner, path),
+            mate,
+            record: 0,
+            buffer: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Refill the buffer with the next record of our mate. Returns false at EOF.
+    fn fill_buffer(&mut self) -> io::Result<bool> {
+        self.buffer.clear();
+        self.pos = 0;
+        loop {
+            let record = match self.inner.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return Ok(false),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            self.record += 1;
+            match mate_of(record.header) {
+                Some(mate) if mate == self.mate => {
+                    record.write_to(&mut self.buffer)?;
+                    return Ok(true);
+                }
+                Some(_) => continue,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Can't tell which mate record {} is from its header: {}", self.record, record.header))),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> io::Read for MateStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() && !self.fill_buffer()? {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+    use super::*;
+
+    fn split(data: &str, mate: Mate) -> io::Result<String> {
+        let mut fastq = String::new();
+        MateStream::new(BufReader::new(data.as_bytes()), mate, "test.fastq").read_to_string(&mut fastq)?;
+        Ok(fastq)
+    }
+
+    #[test]
+    fn test_split_mates() {
+        let interleaved = "@a/1\nAC\n+\nII\n@a/2\nGT\n+\nII\n@b/2\nTT\n+\nII\n@c/1\nCC\n+\nII";
+        assert_eq!(split(interleaved, Mate::R1).unwrap(), "@a/1\nAC\n+\nII\n@c/1\nCC\n+\nII\n");
+        assert_eq!(split(interleaved, Mate::R2).unwrap(), "@a/2\nGT\n+\nII\n@b/2\nTT\n+\nII\n");
+        assert!(split("@a\nAC\n+\nII\n", Mate::R1).is_err());
+        let fasta = ">a/1\nAC\nGT\n>a/2\nTT\n";
+        assert_eq!(split(fasta, Mate::R1).unwrap(), ">a/1\nACGT\n");
+        assert_eq!(split(fasta, Mate::R2).unwrap(), ">a/2\nTT\n");
+    }
+}
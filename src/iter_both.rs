@@ -1,74 +1,282 @@
-use fastq_pair::{create_io, delete_empty_fastq, Output, parse_header, parse_read, PartialRead};
-use std::collections::HashMap;
+use fastq_pair::{create_io, Config, FastqReader, IO, KeySet, Keyed, Mate, Output, PartialRead, Writers};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::BufWriter;
-use std::io::Write;
+use std::io::{BufReader, BufWriter, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use tempfile::TempPath;
 use super::Result;
 
-/// Writes out paired reads to two FASTQ files
-fn write_read(header: &str,
-              w1: &mut BufWriter<File>,
-              w2: &mut BufWriter<File>,
-              map1: &mut HashMap<String, PartialRead>,
-              map2: &mut HashMap<String, PartialRead>) -> Result<()> {
-    let r1 = &map1.remove(header).expect("Failed to remove header from r1 hashamp");
-    let r2 = &map2.remove(header).expect("Failed to remove header from r2 hashmap");
-    write!(w1, "{}.1\n{}+\n{}", header, r1.seq, r1.qscore)?;
-    write!(w2, "{}.2\n{}+\n{}", header, r2.seq, r2.qscore)?;
-    Ok(())
+/// Reads spilled to disk, found again through their offset in the file
+struct Spill {
+    writer: BufWriter<File>,
+    reader: FastqReader<BufReader<File>>,
+    offsets: HashMap<String, u64>,
+    len: u64,
+    /// Removes the file when dropped
+    _path: TempPath,
+}
+
+impl Spill {
+    fn create(dir: &Path) -> Result<Spill> {
+        let file = tempfile::Builder::new().prefix(".fastq_pair").tempfile_in(dir)?;
+        Ok(Spill {
+            writer: BufWriter::new(file.reopen()?),
+            reader: FastqReader::new(BufReader::new(file.reopen()?), "spilled reads"),
+            offsets: HashMap::new(),
+            len: 0,
+            _path: file.into_temp_path(),
+        })
+    }
+}
+
+/// Reads of one mate waiting for their mate. Once they take up more than
+/// `limit` bytes the oldest are spilled to disk until half the limit is
+/// used, keeping only their offsets in memory, which aren't counted.
+struct Waiting {
+    /// Reads by key, numbered in order of arrival
+    reads: HashMap<String, (u64, PartialRead)>,
+    /// Keys of `reads` by arrival, oldest first
+    order: BTreeMap<u64, String>,
+    arrivals: u64,
+    bytes: usize,
+    limit: usize,
+    dir: PathBuf,
+    spill: Option<Spill>,
+}
+
+/// Bytes used by a waiting read, including its key and map entries
+fn held_size(key: &str, read: &PartialRead) -> usize {
+    2 * key.len() + read.size() + size_of::<(String, (u64, PartialRead))>() + size_of::<(u64, String)>()
+}
+
+impl Waiting {
+    fn new(limit: usize, dir: &Path) -> Waiting {
+        Waiting {
+            reads: HashMap::new(),
+            order: BTreeMap::new(),
+            arrivals: 0,
+            bytes: 0,
+            limit,
+            dir: dir.to_path_buf(),
+            spill: None,
+        }
+    }
+
+    /// Whether a read is waiting under `key`, in memory or spilled
+    fn contains(&self, key: &str) -> bool {
+        self.reads.contains_key(key) || self.spill.as_ref().is_some_and(|spill| spill.offsets.contains_key(key))
+    }
+
+    /// Add a read, replacing any waiting under the same key
+    fn insert(&mut self, key: String, read: PartialRead) -> Result<()> {
+        if let Some(spill) = &mut self.spill {
+            spill.offsets.remove(&key);
+        }
+        self.bytes += held_size(&key, &read);
+        self.order.insert(self.arrivals, key.clone());
+        match self.reads.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let (arrival, old) = entry.insert((self.arrivals, read));
+                self.order.remove(&arrival);
+                self.bytes -= held_size(entry.key(), &old);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((self.arrivals, read));
+            }
+        }
+        self.arrivals += 1;
+        if self.bytes > self.limit {
+            self.spill_oldest()?;
+        }
+        Ok(())
+    }
+
+    fn spill_oldest(&mut self) -> Result<()> {
+        if self.spill.is_none() {
+            self.spill = Some(Spill::create(&self.dir)?);
+        }
+        let spill = self.spill.as_mut().expect("spill was just created");
+        let mut record = Vec::new();
+        while self.bytes > self.limit / 2 {
+            let (_, key) = match self.order.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let (_, read) = self.reads.remove(&key).expect("every key in order is waiting");
+            self.bytes -= held_size(&key, &read);
+            record.clear();
+            read.as_record().write_to(&mut record)?;
+            spill.writer.write_all(&record)?;
+            spill.offsets.insert(key, spill.len);
+            spill.len += record.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Remove and return the read waiting under `key`, if any
+    fn take(&mut self, key: &str) -> Result<Option<PartialRead>> {
+        if let Some((arrival, read)) = self.reads.remove(key) {
+            self.order.remove(&arrival);
+            self.bytes -= held_size(key, &read);
+            return Ok(Some(read));
+        }
+        match &mut self.spill {
+            Some(spill) => match spill.offsets.remove(key) {
+                Some(pos) => {
+                    spill.writer.flush()?;
+                    Ok(Some(spill.reader.read_at(pos)?.into()))
+                }
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Write out every read still waiting as a singleton
+    fn write_singletons(self, out: &mut Writers, mate: Mate) -> Result<()> {
+        for (key, (_, read)) in &self.reads {
+            out.write_single(key, &read.as_record(), mate)?;
+        }
+        if let Some(mut spill) = self.spill {
+            spill.writer.flush()?;
+            // In file order so the reader only moves forward
+            let mut offsets: Vec<(String, u64)> = spill.offsets.drain().collect();
+            offsets.sort_unstable_by_key(|&(_, pos)| pos);
+            for (key, pos) in offsets {
+                out.write_single(&key, &spill.reader.read_at(pos)?, mate)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Pair two FASTQ files by iterating over both files simultaneously.
 /// Should be much more memory efficient than "store_read" method if
-/// files are mostly paired
-pub fn pair_fastqs(r1_path: &str, r2_path: &str) -> Result<Output> {
-    let mut io = create_io(r1_path, r2_path)?;
-    let mut map1 = HashMap::new();
-    let mut map2 = HashMap::new();
-    let (mut read1_finished, mut read2_finished) = (false, false);
-    while !(read1_finished && read2_finished) {
-        if let Some(read1) = parse_read(&mut io.in_read1) {
-            let header1 = parse_header(&read1.header)?;
-            map1.insert(header1.clone(), PartialRead { seq: read1.seq, qscore: read1.qscore });
-            if map2.contains_key(&header1) {
-                write_read(&header1, &mut io.out_read1, &mut io.out_read2, &mut map1, &mut map2)?;
+/// files are mostly paired. Reads waiting for their mate are kept within
+/// `max_memory` bytes, split evenly between the mates, by spilling the
+/// oldest to disk.
+pub fn pair_fastqs(r1_path: &str, r2_path: &str, config: &Config, max_memory: Option<u64>) -> Result<Output> {
+    pair_rest(create_io(r1_path, r2_path, config)?, max_memory, [None, None], KeySet::default())
+}
+
+/// Pair whatever is left of `io`'s inputs as above, starting with reads of
+/// read 1 and read 2 already taken from them without finding their mate,
+/// and with keys already paired in `paired`
+pub fn pair_rest(mut io: IO, max_memory: Option<u64>, unpaired: [Option<(String, PartialRead)>; 2],
+                 mut paired: KeySet) -> Result<Output> {
+    let limit = max_memory.map_or(usize::MAX, |max| (max / 2) as usize);
+    let mut waiting1 = Waiting::new(limit, io.temp_dir());
+    let mut waiting2 = Waiting::new(limit, io.temp_dir());
+    let [unpaired1, unpaired2] = unpaired;
+    for (unpaired, waiting, mate) in IntoIterator::into_iter([(unpaired1, &mut waiting1, Mate::R1), (unpaired2, &mut waiting2, Mate::R2)]) {
+        if let Some((key, read)) = unpaired {
+            if !paired.contains(&key) || io.out.duplicate(&key, &read.as_record(), mate, false)? {
+                waiting.insert(key, read)?;
             }
-        } else { read1_finished = true }
-        if let Some(read2) = parse_read(&mut io.in_read2) {
-            let header2 = parse_header(&read2.header)?;
-            map2.insert(header2.clone(), PartialRead { seq: read2.seq, qscore: read2.qscore });
-            if map1.contains_key(&header2) {
-                write_read(&header2, &mut io.out_read1, &mut io.out_read2, &mut map1, &mut map2)?;
-            } else { read2_finished = true }
         }
     }
-    // Write out singletons
-    for key in map1.keys() {
-        let r1 = &map1[key];
-        write!(&mut io.out_single, "{}.1\n{}+\n{}", &key, r1.seq, r1.qscore)?;
+    loop {
+        // Reads are only copied out while they wait for their mate
+        match (io.in_read1.next_record()?, io.in_read2.next_record()?) {
+            (None, None) => break,
+            // Mates side by side, as most are
+            (Some(read1), Some(read2)) if read1.key == read2.key && !waiting1.contains(&read1.key)
+                && !waiting2.contains(&read1.key) && !paired.contains(&read1.key) => {
+                io.out.write_pair(&read1.key, &read1.read, &read2.read)?;
+                if io.out.remember_paired {
+                    paired.insert(&read1.key);
+                }
+            }
+            (read1, read2) => {
+                if let Some(read1) = read1 {
+                    arrive(&mut io.out, read1, Mate::R1, &mut waiting1, &mut waiting2, &mut paired)?;
+                }
+                if let Some(read2) = read2 {
+                    arrive(&mut io.out, read2, Mate::R2, &mut waiting2, &mut waiting1, &mut paired)?;
+                }
+            }
+        }
+    }
+    waiting1.write_singletons(&mut io.out, Mate::R1)?;
+    waiting2.write_singletons(&mut io.out, Mate::R2)?;
+    io.finish()
+}
+
+/// Pair a read with its mate if it's waiting, or else leave the read
+/// waiting for it
+fn arrive(out: &mut Writers, Keyed { key, read, .. }: Keyed, mate: Mate, own: &mut Waiting, other: &mut Waiting,
+          paired: &mut KeySet) -> Result<()> {
+    if let Some(mate_read) = other.take(&key)? {
+        match mate {
+            Mate::R1 => out.write_pair(&key, &read, &mate_read.as_record())?,
+            Mate::R2 => out.write_pair(&key, &mate_read.as_record(), &read)?,
+        }
+        // Only remembered if asked, to spot reads repeating a paired key
+        if out.remember_paired {
+            paired.insert(&key);
+        }
+        return Ok(());
     }
-    for key in map2.keys() {
-        let r2 = &map2[key];
-        write!(&mut io.out_single, "{}.2\n{}+\n{}", &key, r2.seq, r2.qscore)?;
+    // A read whose mate is waiting can't be a duplicate, as the mate
+    // would have been paired with the earlier read
+    let waiting = own.contains(&key);
+    if !(waiting || paired.contains(&key)) || out.duplicate(&key, &read, mate, waiting)? {
+        own.insert(key, PartialRead::from(read))?;
     }
-    // Flush output for empty fastq check
-    io.out_single.flush()?;
-
-    Ok(Output {
-        r1_out_path: io.r1_out_path,
-        r2_out_path: io.r2_out_path,
-        singleton_path: delete_empty_fastq(&io.singleton_path),
-    })
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::copy;
-    use std::io::BufReader;
-    use std::path::Path;
+    use crate::testing::{shuffled_inputs, sorted_records};
+    use fastq_pair::{Counts, OutputNames};
+    use std::fs::{copy, read_dir};
+    use fastq_pair::{open_fastq, parse_header, parse_read};
     use super::*;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_waiting_spills_oldest() {
+        let tmpdir = tempdir().unwrap();
+        let mut reader = open_fastq("data/ncbi_1_paired.fastq").unwrap();
+        let mut reads = Vec::new();
+        while let Some(read) = reader.next_record().unwrap() {
+            reads.push((parse_header(read.header).unwrap(), PartialRead::from(read)));
+        }
+        // Room for about two reads
+        let limit = 2 * held_size(&reads[0].0, &reads[0].1) + 10;
+        let mut waiting = Waiting::new(limit, tmpdir.path());
+        for (key, read) in &reads {
+            waiting.insert(key.clone(), read.clone()).unwrap();
+        }
+        assert!(waiting.bytes <= limit);
+        assert!(waiting.reads.contains_key(&reads[3].0));
+        assert!(waiting.spill.as_ref().unwrap().offsets.contains_key(&reads[0].0));
+        for (key, read) in &reads {
+            let taken = waiting.take(key).unwrap().unwrap();
+            assert_eq!(taken.as_record().seq, read.as_record().seq);
+        }
+        assert!(waiting.take(&reads[0].0).unwrap().is_none());
+        assert_eq!(waiting.bytes, 0);
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let tmpdir = tempdir().unwrap();
+        let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+        let output = pair_fastqs(&r1_path, &r2_path, &Config::default(), None).unwrap();
+        let expected = [sorted_records(Some(&output.r1_out_path)), sorted_records(output.singleton_path.as_deref())];
+        let config = Config { outputs: OutputNames { force: true, ..OutputNames::default() }, ..Config::default() };
+        // Every waiting read is spilled
+        let output = pair_fastqs(&r1_path, &r2_path, &config, Some(1)).unwrap();
+        assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 0 });
+        assert_eq!([sorted_records(Some(&output.r1_out_path)), sorted_records(output.singleton_path.as_deref())], expected);
+        // Spill files are cleaned up
+        assert_eq!(read_dir(tmpdir.path()).unwrap().count(), 5);
+    }
+
     #[test]
     fn test_pair_fastqs() {
         let tmpdir = tempdir().unwrap();
@@ -77,22 +285,20 @@ mod tests {
         let input2 = tmppath.join("ncbi_2_shuffled.fastq");
         copy("data/ncbi_1_shuffled.fastq", &input1).unwrap();
         copy("data/ncbi_2_shuffled.fastq", &input2).unwrap();
-        pair_fastqs(&input1.to_str().unwrap(), &input2.to_str().unwrap()).unwrap();
+        pair_fastqs(input1.to_str().unwrap(), input2.to_str().unwrap(), &Config::default(), None).unwrap();
         // Output exists
         let outputs = [tmppath.join("R1_paired.fastq"),
             tmppath.join("R2_paired.fastq"),
             tmppath.join("Singletons.fastq")];
         for output in &outputs {
-            assert_eq!(Path::exists(Path::new(output)), true);
+            assert!(Path::exists(Path::new(output)));
         }
         // Assert each header in each file matches
-        let h1 = File::open(&outputs[0]).unwrap();
-        let h2 = File::open(&outputs[1]).unwrap();
-        let mut reader1 = BufReader::new(&h1);
-        let mut reader2 = BufReader::new(&h2);
+        let mut reader1 = open_fastq(outputs[0].to_str().unwrap()).unwrap();
+        let mut reader2 = open_fastq(outputs[1].to_str().unwrap()).unwrap();
         for _ in 0..4 {
-            let r1 = parse_read(&mut reader1).unwrap();
-            let r2 = parse_read(&mut reader2).unwrap();
+            let r1 = parse_read(&mut reader1).unwrap().unwrap();
+            let r2 = parse_read(&mut reader2).unwrap().unwrap();
             assert_eq!(parse_header(&r1.header).unwrap(),
                        parse_header(&r2.header).unwrap());
         }
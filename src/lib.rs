@@ -1,140 +1,1157 @@
 use failure::{Fallible, ResultExt};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use regex::Regex;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufWriter};
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+use std::io::{self, BufRead, BufWriter, Cursor, Seek, SeekFrom, Write};
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempfile::{NamedTempFile, TempPath};
+
+pub mod bgzf;
+pub mod interleaved;
+pub mod multiqc;
+pub mod pipeline;
+mod reader;
+pub mod sam;
+pub mod verify;
+
+pub use pipeline::{Keyed, Records};
+pub use reader::{FastqReader, Format, parse_read, ParseError, ParseErrorKind, RecordRef};
 
 pub type Result<T> = Fallible<T>;
 
-// TODO: Make a comprehensive version that also stores descriptor (remainder of header)
-/// Represents a single read from a FASTQ file
+/// Compression applied to output files
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Bgzf,
+}
+
+/// Which end of a read pair a record belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mate {
+    R1,
+    R2,
+}
+
+impl Mate {
+    /// The mate number as written in headers
+    pub fn number(self) -> u8 {
+        match self {
+            Mate::R1 => 1,
+            Mate::R2 => 2,
+        }
+    }
+}
+
+/// What to do with a read whose key was already seen in the same input
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnDuplicate {
+    /// Stop with an error
+    Error,
+    /// Keep the earlier read and drop the later one
+    First,
+    /// Keep the later read. Only methods reading all of read 1 before
+    /// pairing can, and only for read 1; a read 2 arriving after its
+    /// earlier copy was paired is dropped.
+    Last,
+    /// Keep the earlier read and write the later one as a singleton
+    Singletons,
+}
+
+/// Settings shared by all pairing methods
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub compression: Compression,
+    /// Compression level, 0-9
+    pub level: u32,
+    /// User supplied key extraction, overriding header style detection
+    pub key_extractor: Option<HeaderKeyExtractor>,
+    /// Rewrite headers as `<key>.<mate>` rather than copying them verbatim
+    pub normalize_headers: bool,
+    /// Both mates are read from one interleaved FASTQ, split by header suffix
+    pub interleaved: bool,
+    pub outputs: OutputNames,
+    /// Threads to use, counting the one pairing reads. Extra threads parse
+    /// read 1 and read 2, then write each output, in that order.
+    pub threads: usize,
+    pub on_duplicate: OnDuplicate,
+    /// Have methods that pair reads as they go remember a hash of every key
+    /// paired, so they catch any read repeating it as the other methods do.
+    /// Otherwise they only catch a repeat while the earlier read is waiting
+    /// for its mate, which keeps their memory bounded.
+    pub remember_paired: bool,
+}
+
+/// Where outputs are written. By default they go next to read 1 as
+/// `R1_paired.fastq`, `R2_paired.fastq` and `Singletons.fastq`.
+#[derive(Clone, Debug, Default)]
+pub struct OutputNames {
+    /// Directory for outputs that aren't given an explicit path
+    pub dir: Option<String>,
+    /// Prepended to the default names as `<prefix>_R1_paired.fastq`
+    pub prefix: Option<String>,
+    /// Explicit output paths, used as is
+    pub r1: Option<String>,
+    pub r2: Option<String>,
+    pub singletons: Option<String>,
+    /// Write unpaired mates to `R1_singletons.fastq` and `R2_singletons.fastq`
+    /// rather than to a shared singleton file
+    pub split_singletons: bool,
+    pub r1_singletons: Option<String>,
+    pub r2_singletons: Option<String>,
+    /// Write mates alternating into one file, `Interleaved.fastq` unless
+    /// `interleaved` gives a path. `-` writes to stdout.
+    pub interleave: bool,
+    pub interleaved: Option<String>,
+    /// Overwrite outputs that already exist
+    pub force: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            compression: Compression::Plain,
+            level: 6,
+            key_extractor: None,
+            normalize_headers: false,
+            interleaved: false,
+            outputs: OutputNames::default(),
+            threads: 1,
+            on_duplicate: OnDuplicate::Error,
+            remember_paired: false,
+        }
+    }
+}
+
+/// Represents a single read from a FASTQ or FASTA file
 #[derive(Debug)]
 pub struct Read {
     pub header: String,
     pub seq: String,
+    /// The `+` line, including any comment repeated after it
+    pub separator: String,
     pub qscore: String,
 }
 
-impl Read {
-    fn new() -> Read {
-        Read {
-            header: String::new(),
-            seq: String::new(),
-            qscore: String::new(),
+impl fmt::Display for Read {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.header.trim())?;
+        writeln!(f, "{}", self.seq.trim())?;
+        if self.header.starts_with('>') {
+            return Ok(());
         }
+        writeln!(f, "{}", self.separator.trim())?;
+        writeln!(f, "{}", self.qscore.trim())
     }
 }
 
-impl fmt::Display for Read {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> fmt::Result {
-        write!(f, "{}\n", self.header.trim())?;
-        write!(f, "{}\n", self.seq.trim())?;
-        write!(f, "+\n")?;
-        write!(f, "{}\n", self.qscore.trim())
+/// A read held in memory until its mate turns up. Line endings are dropped
+/// but the header and `+` line are kept whole so they can be written back
+/// verbatim. All four lines share one allocation.
+#[derive(Clone, Debug, Default)]
+pub struct PartialRead {
+    data: Vec<u8>,
+    ends: [usize; 4],
+}
+
+impl PartialRead {
+    /// Bytes of the read held on the heap
+    pub fn size(&self) -> usize {
+        self.data.capacity()
+    }
+
+    /// Whether this is the empty read left behind by `mem::take`
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn as_record(&self) -> RecordRef<'_> {
+        let [a, b, c, d] = self.ends;
+        RecordRef {
+            // Copied from a `RecordRef`, whose header is a `str`
+            header: std::str::from_utf8(&self.data[..a]).expect("header is valid UTF-8"),
+            seq: &self.data[a..b],
+            separator: &self.data[b..c],
+            qscore: &self.data[c..d],
+        }
     }
 }
 
-/// Subset of `Read`; excludes header information
-#[derive(Debug)]
-pub struct PartialRead {
-    pub seq: String,
-    pub qscore: String,
+impl From<RecordRef<'_>> for PartialRead {
+    fn from(read: RecordRef) -> PartialRead {
+        let lines = [read.header.as_bytes(), read.seq, read.separator, read.qscore];
+        let mut data = Vec::with_capacity(lines.iter().map(|line| line.len()).sum());
+        let mut ends = [0; 4];
+        for (line, end) in lines.iter().zip(ends.iter_mut()) {
+            data.extend_from_slice(line);
+            *end = data.len();
+        }
+        PartialRead { data, ends }
+    }
+}
+
+/// A FASTQ input; plain files can be seeked while decompressing streams can't
+pub enum Input {
+    File(File),
+    Stream(Box<dyn io::Read + Send>),
+}
+
+impl io::Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::File(f) => f.read(buf),
+            Input::Stream(s) => s.read(buf),
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Input::File(f) => f.seek(pos),
+            Input::Stream(_) => Err(io::Error::other("Can't seek within a compressed or streamed input")),
+        }
+    }
+}
+
+/// Path standing for stdin as an input, or stdout as an output
+pub const STDIO: &str = "-";
+
+/// Where an output's bytes end up
+pub enum Target {
+    File(File),
+    Stdout(io::Stdout),
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Target::File(f) => f.write(buf),
+            Target::Stdout(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Target::File(f) => f.flush(),
+            Target::Stdout(s) => s.flush(),
+        }
+    }
+}
+
+/// A FASTQ output, compressed on the fly if requested
+pub enum Sink {
+    Plain(Target),
+    Gzip(GzEncoder<Target>),
+    Bgzf(bgzf::Writer<Target>),
+    /// Any of the above, written on a thread of its own
+    Threaded(Box<pipeline::SinkThread>),
+}
+
+impl Sink {
+    /// Create `path`, or write to stdout if it's `-`, wrapping it in an
+    /// encoder according to `config`
+    pub fn create(path: &str, config: &Config) -> Result<Sink> {
+        let target = if path == STDIO {
+            Target::Stdout(io::stdout())
+        } else {
+            Target::File(File::create(path)?)
+        };
+        let level = flate2::Compression::new(config.level);
+        Ok(match config.compression {
+            Compression::Plain => Sink::Plain(target),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(target, level)),
+            Compression::Bgzf => Sink::Bgzf(bgzf::Writer::new(target, level)),
+        })
+    }
+
+    /// Write out any trailing compressed data and flush to disk
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Sink::Plain(mut t) => t.flush(),
+            Sink::Gzip(gz) => gz.finish()?.flush(),
+            Sink::Bgzf(bgzf) => bgzf.finish()?.flush(),
+            Sink::Threaded(thread) => thread.finish(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(t) => t.write(buf),
+            Sink::Gzip(gz) => gz.write(buf),
+            Sink::Bgzf(bgzf) => bgzf.write(buf),
+            Sink::Threaded(thread) => thread.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(t) => t.flush(),
+            Sink::Gzip(gz) => gz.flush(),
+            Sink::Bgzf(bgzf) => bgzf.flush(),
+            Sink::Threaded(thread) => thread.flush(),
+        }
+    }
 }
 
-/// Contains all Read/Write objects and paths
+/// Writes a read as it appeared in its input or, if `normalize` is set,
+/// under its pairing key and mate number with a bare `+` line. Either way
+/// the read keeps its input's format.
+pub fn write_read(w: &mut impl Write, key: &str, read: &RecordRef, mate: Mate, normalize: bool) -> io::Result<()> {
+    if !normalize {
+        return read.write_to(w);
+    }
+    writeln!(w, "{}.{}", key, mate.number())?;
+    w.write_all(read.seq)?;
+    if read.format() == Format::Fastq {
+        w.write_all(b"\n+\n")?;
+        w.write_all(read.qscore)?;
+    }
+    w.write_all(b"\n")
+}
+
+/// Number of records written to each kind of output
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    pub pairs: u64,
+    pub r1_singletons: u64,
+    pub r2_singletons: u64,
+    /// Reads whose key was already seen in the same input, dealt with
+    /// according to `Config::on_duplicate`
+    pub duplicates: u64,
+}
+
+/// What a pairing run read and wrote, and how long it took
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairingStats {
+    /// Records read from each input
+    pub r1_records: u64,
+    pub r2_records: u64,
+    pub counts: Counts,
+    /// Uncompressed bytes read from both inputs, and written to all outputs
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub wall_time: Duration,
+}
+
+impl PairingStats {
+    /// Fraction of the records read that were written as part of a pair
+    pub fn pairing_rate(&self) -> f64 {
+        match self.r1_records + self.r2_records {
+            0 => 0.0,
+            records => (2 * self.counts.pairs) as f64 / records as f64,
+        }
+    }
+
+    /// The stats as a flat JSON object
+    pub fn to_json(&self) -> String {
+        let fields = [
+            ("r1_records", self.r1_records.to_string()),
+            ("r2_records", self.r2_records.to_string()),
+            ("pairs", self.counts.pairs.to_string()),
+            ("r1_singletons", self.counts.r1_singletons.to_string()),
+            ("r2_singletons", self.counts.r2_singletons.to_string()),
+            ("duplicates", self.counts.duplicates.to_string()),
+            ("pairing_rate", format!("{:.6}", self.pairing_rate())),
+            ("bytes_read", self.bytes_read.to_string()),
+            ("bytes_written", self.bytes_written.to_string()),
+            ("wall_time_seconds", format!("{:.3}", self.wall_time.as_secs_f64())),
+        ];
+        let body: Vec<String> = fields.iter().map(|(name, value)| format!("  \"{}\": {}", name, value)).collect();
+        format!("{{\n{}\n}}\n", body.join(",\n"))
+    }
+}
+
+/// Passes writes through to `inner`, adding up how many bytes were written
+struct Tally<'a, W> {
+    inner: &'a mut W,
+    bytes: &'a mut u64,
+}
+
+impl<W: Write> Write for Tally<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        *self.bytes += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The paired and singleton outputs. Every method writes through these so
+/// that records look the same whichever method paired them.
+pub struct Writers {
+    /// Read 1 mates, or both mates alternating if `out_read2` isn't set
+    pub out_read1: BufWriter<Sink>,
+    pub out_read2: Option<BufWriter<Sink>>,
+    /// Singletons of both mates, or only of read 1 if `out_single2` is set
+    pub out_single: BufWriter<Sink>,
+    pub out_single2: Option<BufWriter<Sink>>,
+    pub normalize_headers: bool,
+    pub on_duplicate: OnDuplicate,
+    pub remember_paired: bool,
+    pub counts: Counts,
+    /// Uncompressed bytes written to all outputs
+    pub bytes: u64,
+}
+
+impl Writers {
+    /// Write mates sharing `key` to the R1 and R2 outputs
+    pub fn write_pair(&mut self, key: &str, read1: &RecordRef, read2: &RecordRef) -> io::Result<()> {
+        let mut out_read1 = Tally { inner: &mut self.out_read1, bytes: &mut self.bytes };
+        write_read(&mut out_read1, key, read1, Mate::R1, self.normalize_headers)?;
+        let out_read2 = self.out_read2.as_mut().unwrap_or(&mut self.out_read1);
+        write_read(&mut Tally { inner: out_read2, bytes: &mut self.bytes }, key, read2, Mate::R2, self.normalize_headers)?;
+        self.counts.pairs += 1;
+        Ok(())
+    }
+
+    /// Write a read whose mate wasn't found to the singleton output
+    pub fn write_single(&mut self, key: &str, read: &RecordRef, mate: Mate) -> io::Result<()> {
+        let out = match (mate, self.out_single2.as_mut()) {
+            (Mate::R2, Some(out_single2)) => out_single2,
+            _ => &mut self.out_single,
+        };
+        write_read(&mut Tally { inner: out, bytes: &mut self.bytes }, key, read, mate, self.normalize_headers)?;
+        match mate {
+            Mate::R1 => self.counts.r1_singletons += 1,
+            Mate::R2 => self.counts.r2_singletons += 1,
+        }
+        Ok(())
+    }
+
+    /// Deal with a read whose key was already seen in its input, `waiting`
+    /// being whether the earlier read is still waiting for its mate. Returns
+    /// whether the read should take the earlier one's place.
+    pub fn duplicate(&mut self, key: &str, read: &RecordRef, mate: Mate, waiting: bool) -> Result<bool> {
+        self.counts.duplicates += 1;
+        match self.on_duplicate {
+            OnDuplicate::Error => failure::bail!("Key {} appears more than once in the read {} input; see --on-duplicate",
+                                                 key, mate.number()),
+            OnDuplicate::First => Ok(false),
+            OnDuplicate::Last => Ok(waiting),
+            OnDuplicate::Singletons => {
+                self.write_single(key, read, mate)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Flush and close all outputs
+    pub fn finish(self) -> Result<Counts> {
+        let writers = [Some(self.out_read1), self.out_read2, Some(self.out_single), self.out_single2];
+        for writer in IntoIterator::into_iter(writers).flatten() {
+            writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        }
+        Ok(self.counts)
+    }
+}
+
+/// Keys remembered by a 128 bit hash rather than in full, to spot keys seen
+/// before in a fraction of the memory. Two of n keys sharing a hash is
+/// vanishingly unlikely, at about n² in 2^129.
+pub struct KeySet {
+    hasher: RandomState,
+    hashes: HashSet<u128, BuildHasherDefault<Prehashed>>,
+}
+
+/// Hashes a hash by taking its low bits, as it's already evenly spread
+#[derive(Default)]
+struct Prehashed(u64);
+
+impl Hasher for Prehashed {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u128(&mut self, hash: u128) {
+        self.0 = hash as u64;
+    }
+}
+
+impl Default for KeySet {
+    fn default() -> KeySet {
+        KeySet { hasher: RandomState::new(), hashes: HashSet::default() }
+    }
+}
+
+impl KeySet {
+    /// Two finishes of one keyed hash, the second after a further byte
+    fn hash(&self, key: &str) -> u128 {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(key.as_bytes());
+        let high = hasher.finish();
+        hasher.write_u8(0xff);
+        (u128::from(high) << 64) | u128::from(hasher.finish())
+    }
+
+    /// Add a key, returning whether it wasn't already there
+    pub fn insert(&mut self, key: &str) -> bool {
+        let hash = self.hash(key);
+        self.hashes.insert(hash)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.hashes.contains(&self.hash(key))
+    }
+}
+
+/// Contains all Read/Write objects and paths.
+/// For seekable IO, `r1_in_path` and `r2_in_path` point at the uncompressed
+/// copy of a compressed input so they can be reopened for random access.
 pub struct IO {
-    pub in_read1: BufReader<File>,
-    pub in_read2: BufReader<File>,
-    pub out_read1: BufWriter<File>,
-    pub out_read2: BufWriter<File>,
-    pub out_single: BufWriter<File>,
+    pub in_read1: Records<BufReader<Input>>,
+    pub in_read2: Records<BufReader<Input>>,
+    pub out: Writers,
     pub r1_in_path: String,
     pub r2_in_path: String,
     pub r1_out_path: String,
-    pub r2_out_path: String,
+    /// Unset for interleaved output, where both mates go to `r1_out_path`
+    pub r2_out_path: Option<String>,
     pub singleton_path: String,
+    /// Set when read 2 singletons are written separately
+    pub r2_singleton_path: Option<String>,
+    pub key1: HeaderKeyExtractor,
+    pub key2: HeaderKeyExtractor,
+    /// When the inputs were opened, to time the run
+    pub started: Instant,
+    /// Whether the singleton outputs were given their default names, and so
+    /// may be removed if nothing is written to them
+    default_singletons: (bool, bool),
+    // Uncompressed copies of inputs, deleted when IO is dropped
+    _spools: Vec<TempPath>,
 }
 
-/// Subset of IO; contains only output paths
+impl IO {
+    /// Where to keep temporary files: next to the read 1 output, or in the
+    /// working directory if that goes to stdout
+    pub fn temp_dir(&self) -> &Path {
+        match Path::new(&self.r1_out_path).parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    /// Flush and close all outputs, removing default named singleton files
+    /// that are empty
+    pub fn finish(self) -> Result<Output> {
+        let bytes_written = self.out.bytes;
+        let split = self.out.out_single2.is_some();
+        let counts = self.out.finish()?;
+        let (singletons, r2_singletons) = if split {
+            (counts.r1_singletons, counts.r2_singletons)
+        } else {
+            (counts.r1_singletons + counts.r2_singletons, 0)
+        };
+        let (default1, default2) = self.default_singletons;
+        let stats = PairingStats {
+            r1_records: self.in_read1.records(),
+            r2_records: self.in_read2.records(),
+            counts,
+            bytes_read: self.in_read1.bytes() + self.in_read2.bytes(),
+            bytes_written,
+            wall_time: self.started.elapsed(),
+        };
+        Ok(Output {
+            r1_out_path: self.r1_out_path,
+            r2_out_path: self.r2_out_path,
+            singleton_path: remove_unused(self.singleton_path, default1, singletons),
+            r2_singleton_path: self.r2_singleton_path.and_then(|path| remove_unused(path, default2, r2_singletons)),
+            stats,
+        })
+    }
+}
+
+/// Subset of IO; contains only output paths and what was written to them
 pub struct Output {
     pub r1_out_path: String,
-    pub r2_out_path: String,
+    pub r2_out_path: Option<String>,
+    /// Singletons of both mates, or only of read 1 if they were split
     pub singleton_path: Option<String>,
+    pub r2_singleton_path: Option<String>,
+    pub stats: PairingStats,
+}
+
+/// Number of leading records sampled when detecting a file's header style
+const STYLE_SAMPLE_SIZE: usize = 100;
+
+/// Buffer size for the pairing inputs and outputs
+pub const IO_BUFFER_SIZE: usize = 1 << 17;
+
+/// How the mate number is encoded in a read header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderStyle {
+    /// Illumina CASAVA 1.8+: `@A00123:8:HXXX:1:1101:1000:2000 1:N:0:ACGT`
+    Casava,
+    /// Older Illumina: `@HWUSI-EAS100R:6:73:941:1973#0/1`
+    Slash,
+    /// SRA with read ids: `@SRR3380692.1.1 1 length=101`
+    Sra,
+    /// No mate number; the first token is the key as is
+    Plain,
+}
+
+/// Whether a header comment looks like CASAVA 1.8's `<mate>:<filtered>:<control>:<index>`
+fn is_casava_comment(comment: &str) -> bool {
+    let fields: Vec<&str> = comment.split(':').collect();
+    fields.len() >= 4
+        && (fields[0] == "1" || fields[0] == "2")
+        && (fields[1] == "Y" || fields[1] == "N")
+        && !fields[2].is_empty() && fields[2].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The last two characters of a read name, e.g. "/1" or ".2"
+fn mate_suffix(name: &str) -> &str {
+    name.len().checked_sub(2).and_then(|i| name.get(i..)).unwrap_or("")
+}
+
+/// The mate a header belongs to, going by a CASAVA comment or a `/1`, `.1`
+/// style suffix on the read name
+pub fn mate_of(header: &str) -> Option<Mate> {
+    let mut tokens = header.split_whitespace();
+    let name = tokens.next()?;
+    let number = match tokens.next() {
+        Some(comment) if is_casava_comment(comment) => &comment[..1],
+        _ => match mate_suffix(name) {
+            "/1" | ".1" => "1",
+            "/2" | ".2" => "2",
+            _ => return None,
+        },
+    };
+    Some(if number == "1" { Mate::R1 } else { Mate::R2 })
 }
 
-/// Parses a header and returns its unique component
+impl HeaderStyle {
+    /// Pick the style that fits every sampled header. Mate suffixes must be
+    /// the same throughout, so that e.g. SRA reads without read ids
+    /// (`@SRR3380692.1`, `@SRR3380692.2`, ...) aren't mistaken for mates.
+    pub fn detect(headers: &[&str]) -> HeaderStyle {
+        let tokens: Vec<(&str, Option<&str>)> = headers.iter()
+            .map(|h| {
+                let mut split = h.split_whitespace();
+                (split.next().unwrap_or(""), split.next())
+            })
+            .collect();
+        if tokens.is_empty() {
+            return HeaderStyle::Plain;
+        }
+        if tokens.iter().all(|(_, comment)| comment.is_some_and(is_casava_comment)) {
+            return HeaderStyle::Casava;
+        }
+        let first = mate_suffix(tokens[0].0);
+        let consistent = tokens.iter().all(|(name, _)| mate_suffix(name) == first);
+        match first {
+            "/1" | "/2" if consistent => HeaderStyle::Slash,
+            ".1" | ".2" if consistent => HeaderStyle::Sra,
+            _ => HeaderStyle::Plain,
+        }
+    }
+
+    /// Detect the style of a FASTQ or FASTA from its first records, which
+    /// are read ahead without being consumed
+    pub fn detect_file(input: &mut FastqReader<impl BufRead>) -> Result<HeaderStyle> {
+        let headers = input.sample_headers(STYLE_SAMPLE_SIZE)?;
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+        Ok(HeaderStyle::detect(&headers))
+    }
+
+    /// Returns the unique component of a header, shared by both mates
+    pub fn key(self, header: &str) -> Result<String> {
+        let name = match header.split_whitespace().next() {
+            Some(name) => name,
+            None => failure::bail!("Empty FASTQ header"),
+        };
+        let strip = match (self, mate_suffix(name)) {
+            (HeaderStyle::Slash, "/1" | "/2") | (HeaderStyle::Sra, ".1" | ".2") if name.len() > 2 => 2,
+            _ => 0,
+        };
+        Ok(name[..name.len() - strip].to_string())
+    }
+}
+
+/// Extracts the pairing key of a header, using either a built-in header style
+/// or a user supplied pattern
+#[derive(Clone, Debug)]
+pub enum HeaderKeyExtractor {
+    Style(HeaderStyle),
+    Regex(Regex),
+}
+
+impl HeaderKeyExtractor {
+    /// Build an extractor from a pattern with at least one capture group.
+    /// The pattern is matched against the header line without its leading `@`.
+    pub fn from_pattern(pattern: &str) -> Result<HeaderKeyExtractor> {
+        let regex = Regex::new(pattern)?;
+        if regex.captures_len() < 2 {
+            failure::bail!("Key pattern '{}' needs a capture group", pattern);
+        }
+        Ok(HeaderKeyExtractor::Regex(regex))
+    }
+
+    /// Use `config.key_extractor` if given, else detect the style of `input`
+    pub fn for_input(config: &Config, input: &mut FastqReader<impl BufRead>) -> Result<HeaderKeyExtractor> {
+        match &config.key_extractor {
+            Some(extractor) => Ok(extractor.clone()),
+            None => Ok(HeaderKeyExtractor::Style(HeaderStyle::detect_file(input)?)),
+        }
+    }
+
+    /// Returns the unique component of a header, shared by both mates.
+    /// Keys keep the header's leading `@`, or `>` for FASTA.
+    pub fn key(&self, header: &str) -> Result<String> {
+        match self {
+            HeaderKeyExtractor::Style(style) => style.key(header),
+            HeaderKeyExtractor::Regex(regex) => {
+                let line = header.trim_end();
+                let (marker, line) = match line.strip_prefix('>') {
+                    Some(line) => ('>', line),
+                    None => ('@', line.strip_prefix('@').unwrap_or(line)),
+                };
+                match regex.captures(line).and_then(|c| c.get(1)) {
+                    Some(key) => Ok(format!("{}{}", marker, key.as_str())),
+                    None => failure::bail!("Header doesn't match key pattern: {}", line),
+                }
+            }
+        }
+    }
+}
+
+/// Parses a header and returns its unique component, detecting the header style from the header alone
 pub fn parse_header(header: &str) -> Result<String> {
-    let header_vec: Vec<&str> = header.split_whitespace().collect();
-    let uniq = header_vec[0][0..header_vec[0].len() - 2].to_string();
-    Ok(uniq)
+    HeaderKeyExtractor::Style(HeaderStyle::detect(&[header])).key(header)
+}
+
+/// Remove an output no records were written to, returning its path if it's
+/// kept. Only outputs this tool named itself are removed, and only if they
+/// are regular files, so paths given by the user, devices and symlinks stay.
+fn remove_unused(path: String, default_name: bool, records: u64) -> Option<String> {
+    let regular = std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_file());
+    if default_name && records == 0 && regular && std::fs::remove_file(&path).is_ok() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Opens a FASTQ for parsing, see `open_input`
+pub fn open_fastq(path: &str) -> Result<FastqReader<BufReader<Input>>> {
+    Ok(FastqReader::new(BufReader::new(open_input(path, Mate::R1)?), path))
+}
+
+/// Read up to `len` bytes from the start of a stream
+fn read_head(input: &mut impl io::Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(len);
+    io::Read::read_to_end(&mut io::Read::take(input, len as u64), &mut head)?;
+    Ok(head)
+}
+
+/// Whether an input holds SAM text, going by its name or its first bytes
+fn is_sam(path: &str, head: &[u8]) -> bool {
+    path.ends_with(".sam") || path.ends_with(".sam.gz")
+        || (head.len() == 4 && head[0] == b'@' && head[3] == b'\t')
+}
+
+/// Open a path without sniffing its contents. `-` is stdin; pipes such as
+/// `/dev/fd/N` from process substitution are streams as they can't be seeked.
+fn open_raw(path: &str) -> Result<Input> {
+    if path == STDIO {
+        return Ok(Input::Stream(Box::new(io::stdin())));
+    }
+    let mut handle = File::open(path)?;
+    if handle.stream_position().is_ok() {
+        Ok(Input::File(handle))
+    } else {
+        Ok(Input::Stream(Box::new(handle)))
+    }
+}
+
+/// Whether an input can only be read once, front to back
+pub fn is_stream(path: &str) -> Result<bool> {
+    Ok(matches!(open_raw(path)?, Input::Stream(_)))
+}
+
+/// Opens a FASTQ, decompressing on the fly if it starts with the gzip magic
+/// bytes. Concatenated (multi-member) gzip files are read in full.
+/// Unaligned SAM/BAM inputs are converted to FASTQ on the fly; as they hold
+/// both mates, only records belonging to `mate` are returned.
+pub fn open_input(path: &str, mate: Mate) -> Result<Input> {
+    let mut handle = match open_raw(path)? {
+        Input::File(handle) => handle,
+        Input::Stream(stream) => return open_stream(stream, path, mate),
+    };
+    let head = read_head(&mut handle, 4)?;
+    handle.seek(SeekFrom::Start(0))?;
+    if head.starts_with(&[0x1f, 0x8b]) || is_sam(path, &head) {
+        open_stream(Box::new(handle), path, mate)
+    } else {
+        Ok(Input::File(handle))
+    }
+}
+
+/// Sniff the start of a stream to decompress it and convert SAM/BAM as needed.
+/// The bytes read while sniffing are put back in front of the stream.
+fn open_stream(mut stream: Box<dyn io::Read + Send>, path: &str, mate: Mate) -> Result<Input> {
+    let magic = read_head(&mut stream, 2)?;
+    let is_gzip = magic == [0x1f, 0x8b];
+    let stream = io::Read::chain(Cursor::new(magic), stream);
+    let mut stream: Box<dyn io::Read + Send> = if is_gzip {
+        Box::new(MultiGzDecoder::new(BufReader::new(stream)))
+    } else {
+        Box::new(stream)
+    };
+    let head = read_head(&mut stream, 4)?;
+    let is_bam = head == sam::BAM_MAGIC;
+    let is_sam = is_sam(path, &head);
+    let stream = BufReader::new(io::Read::chain(Cursor::new(head), stream));
+    if is_bam {
+        Ok(Input::Stream(Box::new(sam::FastqStream::new(stream, sam::Format::Bam, mate))))
+    } else if is_sam {
+        Ok(Input::Stream(Box::new(sam::FastqStream::new(stream, sam::Format::Sam, mate))))
+    } else {
+        Ok(Input::Stream(Box::new(stream)))
+    }
+}
+
+/// Whether a path holds SAM/BAM rather than FASTQ, in which case both mates
+/// can be read from the same file. Streams can't be checked without consuming them.
+pub fn is_alignment_file(path: &str) -> Result<bool> {
+    let mut handle = File::open(path)?;
+    let mut head = read_head(&mut handle, 4)?;
+    if head.starts_with(&[0x1f, 0x8b]) {
+        handle.seek(SeekFrom::Start(0))?;
+        head = read_head(&mut MultiGzDecoder::new(BufReader::new(handle)), 4)?;
+    }
+    Ok(head == sam::BAM_MAGIC || is_sam(path, &head))
+}
+
+/// Whether a file is gzip or BGZF (including BAM) compressed
+pub fn is_compressed(path: &str) -> Result<bool> {
+    Ok(read_head(&mut File::open(path)?, 2)? == [0x1f, 0x8b])
+}
+
+/// Opens the records of `mate` for parsing, see `open_mate_input`
+pub fn open_mate(path: &str, mate: Mate, config: &Config) -> Result<FastqReader<BufReader<Input>>> {
+    Ok(FastqReader::new(BufReader::new(open_mate_input(path, mate, config)?), path))
+}
+
+/// Opens the records of `mate`, splitting them out of an interleaved input if need be
+fn open_mate_input(path: &str, mate: Mate, config: &Config) -> Result<Input> {
+    let input = open_input(path, mate)?;
+    if config.interleaved {
+        Ok(Input::Stream(Box::new(interleaved::MateStream::new(BufReader::new(input), mate, path))))
+    } else {
+        Ok(input)
+    }
+}
+
+/// Opens a FASTQ for random access. Compressed inputs are decompressed into a
+/// temporary file, whose path is returned alongside it.
+pub fn open_seekable_input(path: &str, mate: Mate) -> Result<(Input, Option<TempPath>)> {
+    spool(open_input(path, mate)?)
 }
 
-/// Deletes empty FASTQ by parsing read to see if it's reached EOF
-pub fn delete_empty_fastq(file_path: &str) -> Option<String> {
-    let handle = File::open(file_path).expect("Failed to open fastq");
-    let mut singleton_reader = BufReader::new(handle);
-    match parse_read(&mut singleton_reader) {
-        Some(_) => return Some(file_path.to_string()),
-        None => {
-            std::fs::remove_file(file_path).ok()?;
-            return None;
+/// Copy the raw bytes of a streamed input into a temporary file, so an input
+/// holding both mates can be read twice. Returns None for regular files.
+fn spool_raw(path: &str) -> Result<Option<TempPath>> {
+    match open_raw(path)? {
+        Input::Stream(mut stream) => {
+            let mut spool = NamedTempFile::new()?;
+            io::copy(&mut stream, &mut spool)?;
+            Ok(Some(spool.into_temp_path()))
         }
+        Input::File(_) => Ok(None),
     }
 }
 
-/// Parses Read struct from BufReader object
-pub fn parse_read(file: &mut impl BufRead) -> Option<Read> {
-    let mut sep = String::new();
-    let mut read = Read::new();
-    // read_line returns a Result<u32> of bytes of the line. EOF is length zero, so we'll break
-    if file.read_line(&mut read.header).ok()? == 0 { return None; }
-    file.read_line(&mut read.seq).ok()?;
-    file.read_line(&mut sep).ok()?;
-    file.read_line(&mut read.qscore).ok()?;
-    Some(read)
+/// Copy a stream into a temporary file so it can be seeked
+fn spool(input: Input) -> Result<(Input, Option<TempPath>)> {
+    match input {
+        Input::Stream(mut stream) => {
+            let mut spool = NamedTempFile::new()?;
+            io::copy(&mut stream, &mut spool)?;
+            let mut handle = spool.reopen()?;
+            handle.seek(SeekFrom::Start(0))?;
+            Ok((Input::File(handle), Some(spool.into_temp_path())))
+        }
+        input => Ok((input, None)),
+    }
 }
 
 /// Create all IO objects for reading and writing
-pub fn create_io(r1_path: &str, r2_path: &str) -> Result<IO> {
-    let parent = Path::new(r1_path).parent().expect("Failed to get parent path");
-    let r1_out_path = parent.join("R1_paired.fastq").to_str()
-        .expect("Failed to convert R1 path to str").to_string();
-    let r2_out_path = parent.join("R2_paired.fastq").to_str()
-        .expect("Failed to convert R2 path to str").to_string();
-    let singleton_path = parent.join("Singletons.fastq").to_str()
-        .expect("Failed to convert singleton path to str").to_string();
+pub fn create_io(r1_path: &str, r2_path: &str, config: &Config) -> Result<IO> {
+    build_io(r1_path, r2_path, config, false)
+}
+
+/// Create all IO objects, ensuring both inputs can be seeked
+pub fn create_seekable_io(r1_path: &str, r2_path: &str, config: &Config) -> Result<IO> {
+    build_io(r1_path, r2_path, config, true)
+}
+
+/// Strip compression and format extensions from a file name, e.g.
+/// `sample_R1.fastq.gz` becomes `sample_R1`
+fn file_stem(path: &str) -> String {
+    let mut name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path);
+    name = name.strip_suffix(".gz").unwrap_or(name);
+    for ext in &[".fastq", ".fq", ".fasta", ".fa", ".sam", ".bam"] {
+        name = name.strip_suffix(ext).unwrap_or(name);
+    }
+    name.to_string()
+}
+
+/// Parse a size in bytes with an optional binary `K`, `M`, `G` or `T`
+/// suffix, e.g. `512M` or `1.5G`
+pub fn parse_size(size: &str) -> Result<u64> {
+    let upper = size.trim().to_ascii_uppercase();
+    let number = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, shift) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 10),
+        Some('M') => (&number[..number.len() - 1], 20),
+        Some('G') => (&number[..number.len() - 1], 30),
+        Some('T') => (&number[..number.len() - 1], 40),
+        _ => (number, 0),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0.0 => Ok((n * (1u64 << shift) as f64) as u64),
+        _ => failure::bail!("Invalid size '{}'; expected e.g. 512M or 8G", size),
+    }
+}
+
+/// Format a size in bytes for humans, e.g. `1.5 GB`
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, units[unit]) }
+}
+
+/// Derive an output prefix from the input names: the part of the file names
+/// both mates share, minus the trailing mate marker. `sample_R1_001.fastq.gz`
+/// and `sample_R2_001.fastq.gz` give `sample`.
+pub fn derive_prefix(r1_path: &str, r2_path: &str) -> String {
+    let (stem1, stem2) = (file_stem(r1_path), file_stem(r2_path));
+    if stem1 == stem2 {
+        return stem1;
+    }
+    let common = stem1.char_indices()
+        .zip(stem2.chars())
+        .find(|((_, c1), c2)| c1 != c2)
+        .map_or(&stem1[..], |((i, _), _)| &stem1[..i]);
+    let separators = ['_', '-', '.'];
+    let prefix = common.strip_suffix(['R', 'r'])
+        .filter(|p| p.ends_with(separators))
+        .unwrap_or(common)
+        .trim_end_matches(separators);
+    if prefix.is_empty() { stem1 } else { prefix.to_string() }
+}
+
+/// Resolved output paths; read 2 paths are only set when those records
+/// get a file of their own
+struct OutputPaths {
+    r1: String,
+    r2: Option<String>,
+    singletons: String,
+    r2_singletons: Option<String>,
+}
+
+/// Resolve where each output goes, refusing to overwrite existing files
+/// unless forced, and inputs at all. Default names take their extension
+/// from `format`.
+fn output_paths(r1_path: &str, r2_path: &str, config: &Config, format: Format) -> Result<OutputPaths> {
+    let names = &config.outputs;
+    let ext = match config.compression {
+        Compression::Plain => format.extension().to_string(),
+        Compression::Gzip | Compression::Bgzf => format!("{}.gz", format.extension()),
+    };
+    let dir = match &names.dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).context(format!("Can't create output directory {}", dir))?;
+            Path::new(dir)
+        }
+        // Next to read 1, or in the working directory if it's streamed
+        None if is_stream(r1_path)? => Path::new("."),
+        None => Path::new(r1_path).parent().expect("Failed to get parent path"),
+    };
+    let prefix = names.prefix.as_ref().map_or(String::new(), |p| format!("{}_", p));
+    let path = |explicit: &Option<String>, name: &str| match explicit {
+        Some(path) => path.clone(),
+        None => dir.join(format!("{}{}.{}", prefix, name, ext)).to_str()
+            .expect("Failed to convert output path to str").to_string(),
+    };
+    let (r1, r2) = if names.interleave {
+        (path(&names.interleaved, "Interleaved"), None)
+    } else {
+        (path(&names.r1, "R1_paired"), Some(path(&names.r2, "R2_paired")))
+    };
+    let (singletons, r2_singletons) = if names.split_singletons {
+        (path(&names.r1_singletons, "R1_singletons"), Some(path(&names.r2_singletons, "R2_singletons")))
+    } else {
+        (path(&names.singletons, "Singletons"), None)
+    };
+    let paths = OutputPaths { r1, r2, singletons, r2_singletons };
+    let all: Vec<&String> = [Some(&paths.r1), paths.r2.as_ref(), Some(&paths.singletons), paths.r2_singletons.as_ref()]
+        .iter().flatten().cloned().collect();
+    // Inputs as they resolve once symlinks are followed
+    let inputs: Vec<PathBuf> = [r1_path, r2_path].iter()
+        .filter(|&&path| path != STDIO)
+        .filter_map(|path| std::fs::canonicalize(path).ok())
+        .collect();
+    for (i, out) in all.iter().enumerate() {
+        if all[..i].contains(out) {
+            failure::bail!("Output {} is given more than once", out);
+        }
+        if out.as_str() == STDIO {
+            continue;
+        }
+        if std::fs::canonicalize(out).is_ok_and(|resolved| inputs.contains(&resolved)) {
+            failure::bail!("Output {} is also an input", out);
+        }
+        // Devices such as /dev/null, and pipes, aren't clobbered by writing to them
+        if !names.force && Path::new(out).is_file() {
+            failure::bail!("Output {} already exists; use --force to overwrite it", out);
+        }
+    }
+    Ok(paths)
+}
+
+fn build_io(r1_path: &str, r2_path: &str, config: &Config, seekable: bool) -> Result<IO> {
+    let started = Instant::now();
     // Readers
-    let r1_handle = File::open(&r1_path).context("Can't open read1 file")?;
-    let r2_handle = File::open(&r2_path).context("Can't open read2 file")?;
-    let r1_reader = BufReader::new(r1_handle);
-    let r2_reader = BufReader::new(r2_handle);
-    // Writers
-    let r1_out_handle = File::create(&r1_out_path).context("Can't create read1 output file")?;
-    let r2_out_handle = File::create(&r2_out_path).context("Can't create read2 output file")?;
-    let singleton_handle = File::create(&singleton_path).context("Can't create singleton output file")?;
-    let r1_writer = BufWriter::new(r1_out_handle);
-    let r2_writer = BufWriter::new(r2_out_handle);
-    let singleton_writer = BufWriter::new(singleton_handle);
+    let mut r1_in_path = r1_path.to_string();
+    let mut r2_in_path = r2_path.to_string();
+    let mut spools = Vec::new();
+    if r1_path == r2_path {
+        // Both mates are read from one input, which can't be streamed twice
+        if let Some(spool) = spool_raw(r1_path).context("Can't read input")? {
+            r1_in_path = spool.to_str().expect("Failed to convert spool path to str").to_string();
+            r2_in_path = r1_in_path.clone();
+            spools.push(spool);
+        }
+        if !config.interleaved && !is_alignment_file(&r1_in_path)? {
+            failure::bail!("Both mates can only be read from one input if it's SAM/BAM or interleaved");
+        }
+    }
+    let (r1_name, r2_name) = (r1_path, r2_path);
+    let (r1_path, r2_path) = (r1_in_path.clone(), r2_in_path.clone());
+    let (r1_handle, r2_handle);
+    if seekable {
+        let (handle, spool1) = spool(open_mate_input(&r1_path, Mate::R1, config).context("Can't open read1 file")?)?;
+        r1_handle = handle;
+        let (handle, spool2) = spool(open_mate_input(&r2_path, Mate::R2, config).context("Can't open read2 file")?)?;
+        r2_handle = handle;
+        if let Some(spool) = spool1 {
+            r1_in_path = spool.to_str().expect("Failed to convert spool path to str").to_string();
+            spools.push(spool);
+        }
+        if let Some(spool) = spool2 {
+            r2_in_path = spool.to_str().expect("Failed to convert spool path to str").to_string();
+            spools.push(spool);
+        }
+    } else {
+        r1_handle = open_mate_input(&r1_path, Mate::R1, config).context("Can't open read1 file")?;
+        r2_handle = open_mate_input(&r2_path, Mate::R2, config).context("Can't open read2 file")?;
+    }
+    // Errors name the inputs as given rather than any spooled copy
+    let mut r1_reader = FastqReader::new(BufReader::with_capacity(IO_BUFFER_SIZE, r1_handle), r1_name);
+    let mut r2_reader = FastqReader::new(BufReader::with_capacity(IO_BUFFER_SIZE, r2_handle), r2_name);
+    let key1 = HeaderKeyExtractor::for_input(config, &mut r1_reader).context("Can't read read1 file")?;
+    let key2 = HeaderKeyExtractor::for_input(config, &mut r2_reader).context("Can't read read2 file")?;
+    // Writers, in the format of the inputs
+    let format = Format::detect(&mut r1_reader).context("Can't read read1 file")?;
+    let OutputPaths { r1: r1_out_path, r2: r2_out_path, singletons: singleton_path, r2_singletons: r2_singleton_path } =
+        output_paths(r1_name, r2_name, config, format)?;
+    let names = &config.outputs;
+    let default_singletons = if names.split_singletons {
+        (names.r1_singletons.is_none(), names.r2_singletons.is_none())
+    } else {
+        (names.singletons.is_none(), false)
+    };
+    // Threads beyond the pairing one go to each stage in turn
+    let mut spare_threads = config.threads.saturating_sub(1);
+    let mut spare_thread = || {
+        let spare = spare_threads > 0;
+        spare_threads = spare_threads.saturating_sub(1);
+        spare
+    };
+    let in_read1 = if spare_thread() { Records::spawn(r1_reader, key1.clone()) } else { Records::new(r1_reader, key1.clone()) };
+    let in_read2 = if spare_thread() { Records::spawn(r2_reader, key2.clone()) } else { Records::new(r2_reader, key2.clone()) };
+    let mut create = |path: &str, what: &str| -> Result<BufWriter<Sink>> {
+        let mut sink = Sink::create(path, config).context(format!("Can't create {} output file", what))?;
+        if spare_thread() {
+            sink = Sink::Threaded(Box::new(pipeline::SinkThread::spawn(sink)));
+        }
+        Ok(BufWriter::with_capacity(IO_BUFFER_SIZE, sink))
+    };
+    let r1_writer = create(&r1_out_path, "read1")?;
+    let r2_writer = r2_out_path.as_deref().map(|path| create(path, "read2")).transpose()?;
+    let singleton_writer = create(&singleton_path, "singleton")?;
+    let r2_singleton_writer = r2_singleton_path.as_deref().map(|path| create(path, "read2 singleton")).transpose()?;
     Ok(IO {
-        in_read1: r1_reader,
-        in_read2: r2_reader,
-        out_read1: r1_writer,
-        out_read2: r2_writer,
-        out_single: singleton_writer,
-        r1_in_path: r1_path.to_string(),
-        r2_in_path: r2_path.to_string(),
+        in_read1,
+        in_read2,
+        out: Writers {
+            out_read1: r1_writer,
+            out_read2: r2_writer,
+            out_single: singleton_writer,
+            out_single2: r2_singleton_writer,
+            normalize_headers: config.normalize_headers,
+            on_duplicate: config.on_duplicate,
+            remember_paired: config.remember_paired,
+            counts: Counts::default(),
+            bytes: 0,
+        },
+        r1_in_path,
+        r2_in_path,
         r1_out_path,
         r2_out_path,
         singleton_path,
+        r2_singleton_path,
+        key1,
+        key2,
+        started,
+        default_singletons,
+        _spools: spools,
     })
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::File;
-    use std::io::BufReader;
+    use std::io::{BufReader, Read, Write};
     use super::*;
+    use tempfile::tempdir;
+
+    /// Gzip `data` as two concatenated members, like `cat a.gz b.gz`
+    fn write_multi_member_gz(path: &Path, data: &str) {
+        let (first, second) = data.split_at(data.len() / 2);
+        let mut handle = File::create(path).unwrap();
+        for part in &[first, second] {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(part.as_bytes()).unwrap();
+            handle.write_all(&encoder.finish().unwrap()).unwrap();
+        }
+    }
 
     #[test]
     fn test_parse_header() {
@@ -142,18 +1159,291 @@ mod tests {
         assert_eq!("@foo:bar:UUID".to_string(), parse_header(header).unwrap());
     }
 
+    #[test]
+    fn test_header_styles() {
+        let casava = "@A00123:8:HXXX:1:1101:1000:2000 1:N:0:ACGT";
+        assert_eq!(HeaderStyle::detect(&[casava]), HeaderStyle::Casava);
+        assert_eq!(parse_header(casava).unwrap(), "@A00123:8:HXXX:1:1101:1000:2000");
+        assert_eq!(parse_header("@A00123:8:HXXX:1:1101:1000:2000 2:Y:18:ACGT").unwrap(),
+                   "@A00123:8:HXXX:1:1101:1000:2000");
+        assert_eq!(parse_header("@HWUSI-EAS100R:6:73:941:1973#0/2").unwrap(), "@HWUSI-EAS100R:6:73:941:1973#0");
+        assert_eq!(parse_header("@SRR3380692.1.1 1 length=101").unwrap(), "@SRR3380692.1");
+        // Only a mate suffix is stripped, whatever the name ends in
+        assert_eq!(HeaderStyle::Slash.key("@read/1").unwrap(), "@read");
+        assert_eq!(HeaderStyle::Slash.key("@read_é").unwrap(), "@read_é");
+        assert_eq!(HeaderStyle::Sra.key("@SRR1.1.é").unwrap(), "@SRR1.1.é");
+    }
+
+    #[test]
+    fn test_mate_of() {
+        assert_eq!(mate_of("@A00123:8:HXXX:1:1101:1000:2000 2:N:0:ACGT"), Some(Mate::R2));
+        assert_eq!(mate_of("@HWUSI-EAS100R:6:73:941:1973#0/1"), Some(Mate::R1));
+        assert_eq!(mate_of("@SRR3380692.1.2 1 length=101"), Some(Mate::R2));
+        assert_eq!(mate_of("@read"), None);
+    }
+
+    #[test]
+    fn test_key_pattern() {
+        let extractor = HeaderKeyExtractor::from_pattern(r"^run7_(\w+)_lane\d_R[12]").unwrap();
+        assert_eq!(extractor.key("@run7_ABC123_lane2_R1 extra\n").unwrap(), "@ABC123");
+        assert_eq!(extractor.key("@run7_ABC123_lane2_R2\n").unwrap(), "@ABC123");
+        assert!(extractor.key("@other").is_err());
+        assert!(HeaderKeyExtractor::from_pattern(r"^run7_\w+").is_err());
+    }
+
+    #[test]
+    fn test_detect_file() {
+        let mut reader = open_fastq("data/ncbi_1_shuffled.fastq").unwrap();
+        assert_eq!(HeaderStyle::detect_file(&mut reader).unwrap(), HeaderStyle::Sra);
+        // Detection doesn't consume the buffer
+        assert_eq!(parse_read(&mut reader).unwrap().unwrap().header, "@SRR3380692.3.1 3 length=101\n");
+        // Streams hand over the few bytes sniffed from them first
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(include_bytes!("../data/ncbi_1_shuffled.fastq")).unwrap();
+        let input = open_stream(Box::new(Cursor::new(encoder.finish().unwrap())), STDIO, Mate::R1).unwrap();
+        let mut reader = FastqReader::new(BufReader::new(input), STDIO);
+        assert_eq!(HeaderStyle::detect_file(&mut reader).unwrap(), HeaderStyle::Sra);
+        // SRA read numbers without a mate suffix vary, so they are kept whole
+        let style = HeaderStyle::detect(&["@SRR3380692.1 1 length=101", "@SRR3380692.2 2 length=101"]);
+        assert_eq!(style, HeaderStyle::Plain);
+        assert_eq!(style.key("@SRR3380692.1 1 length=101").unwrap(), "@SRR3380692.1");
+    }
+
     #[test]
     fn test_parse_read() {
-        let handle = File::open("data/ncbi_1_paired.fastq").unwrap();
-        let mut reader = BufReader::new(handle);
+        let mut reader = open_fastq("data/ncbi_1_paired.fastq").unwrap();
         let header = "@SRR3380692.1.1 1 length=101\n";
         let seq = "ATTGTNTTATTCTATAAAACATTTCAAACCTAGTTAGAGATTTGTAATCAAA\
                     AAACATTTGCGCAGAAAGCAGCACTTAGGGCTGCCTGTTCTATACCCTA\n";
         let qscore = "@@@DD#4AFHHHHJJJJIJJJJJJJJJJJJIIJHGJJIJJJIJJGHGIIJ\
                     JJJIJJJJJJJJIJJHHHFFFFFEEEEEDDDDDDDDDDDCCDEEEFDCDDC\n";
-        let read = parse_read(&mut reader).unwrap();
+        let read = parse_read(&mut reader).unwrap().unwrap();
         assert_eq!(header.to_string(), read.header);
         assert_eq!(seq.to_string(), read.seq);
         assert_eq!(qscore.to_string(), read.qscore);
     }
+
+    #[test]
+    fn test_write_read() {
+        let mut reader = FastqReader::new("@SRR1.1.1 1 length=4\nACGT\n+SRR1.1.1 1 length=4\nIIII\n".as_bytes(), "test");
+        let read: PartialRead = reader.next_record().unwrap().unwrap().into();
+        let read = read.as_record();
+        let mut verbatim = Vec::new();
+        write_read(&mut verbatim, "@SRR1.1", &read, Mate::R1, false).unwrap();
+        assert_eq!(verbatim, b"@SRR1.1.1 1 length=4\nACGT\n+SRR1.1.1 1 length=4\nIIII\n");
+        let mut normalized = Vec::new();
+        write_read(&mut normalized, "@SRR1.1", &read, Mate::R2, true).unwrap();
+        assert_eq!(normalized, b"@SRR1.1.2\nACGT\n+\nIIII\n");
+    }
+
+    #[test]
+    fn test_open_input_gzip() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("ncbi_1_paired.fastq.gz");
+        let fastq = include_str!("../data/ncbi_1_paired.fastq");
+        write_multi_member_gz(&path, fastq);
+        let mut input = open_input(path.to_str().unwrap(), Mate::R1).unwrap();
+        let mut contents = String::new();
+        input.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, fastq);
+        assert!(input.seek(SeekFrom::Start(0)).is_err());
+        // Input is left untouched
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_open_stream() {
+        let fastq = include_str!("../data/ncbi_1_paired.fastq");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(fastq.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        for data in [fastq.as_bytes().to_vec(), gzipped] {
+            let mut input = open_stream(Box::new(Cursor::new(data)), STDIO, Mate::R1).unwrap();
+            let mut contents = String::new();
+            input.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, fastq);
+        }
+        let sam = "@HD\tVN:1.6\nr1\t77\t*\t0\t0\t*\t*\t0\t0\tACGTA\tABCDE\n";
+        let mut input = open_stream(Box::new(Cursor::new(sam)), STDIO, Mate::R1).unwrap();
+        let mut contents = String::new();
+        input.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "@r1/1\nACGTA\n+\nABCDE\n");
+        assert!(is_stream(STDIO).unwrap());
+        assert!(!is_stream("data/ncbi_1_paired.fastq").unwrap());
+    }
+
+    #[test]
+    fn test_shared_input_format() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("ncbi_1_paired.fastq");
+        std::fs::copy("data/ncbi_1_paired.fastq", &path).unwrap();
+        let path = path.to_str().unwrap();
+        assert!(create_io(path, path, &Config::default()).is_err());
+        assert!(create_io(path, path, &Config { interleaved: true, ..Config::default() }).is_ok());
+    }
+
+    #[test]
+    fn test_open_seekable_input_gzip() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("ncbi_1_paired.fastq.gz");
+        write_multi_member_gz(&path, include_str!("../data/ncbi_1_paired.fastq"));
+        let (input, spool) = open_seekable_input(path.to_str().unwrap(), Mate::R1).unwrap();
+        let spool = spool.unwrap();
+        let mut reader = FastqReader::new(BufReader::new(input), "spool");
+        reader.seek(SeekFrom::Start(235)).unwrap();
+        let read = parse_read(&mut reader).unwrap().unwrap();
+        assert_eq!(read.header, "@SRR3380692.4.1 4 length=101\n");
+        let spool_path = spool.to_path_buf();
+        drop(spool);
+        assert!(!spool_path.exists());
+    }
+
+    #[test]
+    fn test_compressed_output() {
+        let tmpdir = tempdir().unwrap();
+        let r1_path = tmpdir.path().join("ncbi_1_paired.fastq");
+        let r2_path = tmpdir.path().join("ncbi_2_paired.fastq");
+        std::fs::copy("data/ncbi_1_paired.fastq", &r1_path).unwrap();
+        std::fs::copy("data/ncbi_2_paired.fastq", &r2_path).unwrap();
+        for compression in &[Compression::Gzip, Compression::Bgzf] {
+            let outputs = OutputNames { force: true, ..OutputNames::default() };
+            let config = Config { compression: *compression, outputs, ..Config::default() };
+            let mut io = create_io(r1_path.to_str().unwrap(), r2_path.to_str().unwrap(), &config).unwrap();
+            while let Some(keyed) = io.in_read1.next_record().unwrap() {
+                keyed.read.write_to(&mut io.out.out_read1).unwrap();
+            }
+            let output = io.finish().unwrap();
+            assert!(output.r1_out_path.ends_with("R1_paired.fastq.gz"));
+            assert_eq!(output.singleton_path, None);
+            let mut contents = String::new();
+            open_input(&output.r1_out_path, Mate::R1).unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, include_str!("../data/ncbi_1_paired.fastq"));
+        }
+    }
+
+    #[test]
+    fn test_derive_prefix() {
+        assert_eq!(derive_prefix("data/sample_R1_001.fastq.gz", "data/sample_R2_001.fastq.gz"), "sample");
+        assert_eq!(derive_prefix("ncbi_1_shuffled.fastq", "ncbi_2_shuffled.fastq"), "ncbi");
+        assert_eq!(derive_prefix("reads.bam", "reads.bam"), "reads");
+        assert_eq!(derive_prefix("tumour1.fq", "tumour2.fq"), "tumour");
+        assert_eq!(derive_prefix("1.fq", "2.fq"), "1");
+    }
+
+    #[test]
+    fn test_key_set() {
+        let mut keys = KeySet::default();
+        assert!(keys.insert("@SRR3380692.1"));
+        assert!(keys.insert("@SRR3380692.10"));
+        assert!(!keys.insert("@SRR3380692.1"));
+        assert!(keys.contains("@SRR3380692.10"));
+        assert!(!keys.contains("@SRR3380692.2"));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert_eq!(format_size(1000), "1000 B");
+        assert_eq!(format_size(3 << 29), "1.5 GB");
+    }
+
+    #[test]
+    fn test_output_paths() {
+        let tmpdir = tempdir().unwrap();
+        let r1_path = tmpdir.path().join("s_1.fastq");
+        let r1_path = r1_path.to_str().unwrap();
+        let out_dir = tmpdir.path().join("out");
+        let outputs = OutputNames {
+            dir: Some(out_dir.to_str().unwrap().to_string()),
+            prefix: Some("s".to_string()),
+            singletons: Some(tmpdir.path().join("lonely.fastq").to_str().unwrap().to_string()),
+            ..OutputNames::default()
+        };
+        let config = Config { outputs, ..Config::default() };
+        let paths = output_paths(r1_path, r1_path, &config, Format::Fastq).unwrap();
+        assert_eq!(paths.r1, out_dir.join("s_R1_paired.fastq").to_str().unwrap());
+        assert_eq!(paths.r2.as_deref(), out_dir.join("s_R2_paired.fastq").to_str());
+        assert!(paths.singletons.ends_with("lonely.fastq"));
+        assert_eq!(paths.r2_singletons, None);
+        // Existing outputs are only replaced when forced
+        File::create(paths.r2.unwrap()).unwrap();
+        assert!(output_paths(r1_path, r1_path, &config, Format::Fastq).is_err());
+        let mut config = config;
+        config.outputs.force = true;
+        assert!(output_paths(r1_path, r1_path, &config, Format::Fastq).is_ok());
+        config.outputs.r1 = Some(paths.singletons.clone());
+        assert!(output_paths(r1_path, r1_path, &config, Format::Fastq).is_err());
+        // Inputs are never overwritten, even when forced or through a symlink
+        File::create(r1_path).unwrap();
+        let link = tmpdir.path().join("link.fastq");
+        std::os::unix::fs::symlink(r1_path, &link).unwrap();
+        for out in &[r1_path, link.to_str().unwrap()] {
+            config.outputs.r1 = Some(out.to_string());
+            let err = output_paths(r1_path, r1_path, &config, Format::Fastq).err().unwrap();
+            assert!(err.to_string().contains("is also an input"), "{}", err);
+        }
+        // Only regular files count as existing
+        config.outputs.force = false;
+        config.outputs.r1 = Some("/dev/null".to_string());
+        config.outputs.r2 = Some(tmpdir.path().join("r2.fastq").to_str().unwrap().to_string());
+        assert!(output_paths(r1_path, r1_path, &config, Format::Fastq).is_ok());
+        config.outputs.force = true;
+        config.outputs.r1 = None;
+        config.outputs.r2 = None;
+        config.outputs.split_singletons = true;
+        let paths = output_paths(r1_path, r1_path, &config, Format::Fastq).unwrap();
+        assert_eq!(paths.singletons, out_dir.join("s_R1_singletons.fastq").to_str().unwrap());
+        assert_eq!(paths.r2_singletons.as_deref(), out_dir.join("s_R2_singletons.fastq").to_str());
+        config.outputs.interleave = true;
+        config.outputs.interleaved = Some(STDIO.to_string());
+        let paths = output_paths(r1_path, r1_path, &config, Format::Fastq).unwrap();
+        assert_eq!(paths.r1, STDIO);
+        assert_eq!(paths.r2, None);
+    }
+
+    #[test]
+    fn test_unused_singletons() {
+        let tmpdir = tempdir().unwrap();
+        let r1_path = tmpdir.path().join("ncbi_1_paired.fastq");
+        std::fs::copy("data/ncbi_1_paired.fastq", &r1_path).unwrap();
+        let r1_path = r1_path.to_str().unwrap();
+        // An empty default named singleton file is removed
+        let io = create_io(r1_path, "data/ncbi_2_paired.fastq", &Config::default()).unwrap();
+        let default_path = io.singleton_path.clone();
+        assert_eq!(io.finish().unwrap().singleton_path, None);
+        assert!(!Path::new(&default_path).exists());
+        // One given explicitly is kept, even when forced over an existing file
+        let explicit = tmpdir.path().join("lonely.fastq").to_str().unwrap().to_string();
+        File::create(&explicit).unwrap();
+        let outputs = OutputNames { singletons: Some(explicit.clone()), force: true, ..OutputNames::default() };
+        let io = create_io(r1_path, "data/ncbi_2_paired.fastq", &Config { outputs, ..Config::default() }).unwrap();
+        assert_eq!(io.finish().unwrap().singleton_path, Some(explicit.clone()));
+        assert!(Path::new(&explicit).exists());
+    }
+
+    #[test]
+    fn test_open_input_bam() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("unaligned.bam");
+        let bam = sam::tests::encode_bam(&[("r1", 77, "ACGTA", "ABCDE"), ("r2", 77, "GGGGG", "IIIII"),
+                                           ("r1", 141, "AACCG", "FGHIJ")]);
+        let mut writer = bgzf::Writer::new(File::create(&path).unwrap(), flate2::Compression::default());
+        writer.write_all(&bam).unwrap();
+        writer.finish().unwrap();
+        let path = path.to_str().unwrap();
+        assert!(is_alignment_file(path).unwrap());
+        let mut reader1 = FastqReader::new(BufReader::new(open_input(path, Mate::R1).unwrap()), path);
+        let mut reader2 = FastqReader::new(BufReader::new(open_input(path, Mate::R2).unwrap()), path);
+        let read1 = parse_read(&mut reader1).unwrap().unwrap();
+        let read2 = parse_read(&mut reader2).unwrap().unwrap();
+        assert_eq!(parse_header(&read1.header).unwrap(), parse_header(&read2.header).unwrap());
+        assert_eq!(read2.seq, "AACCG\n");
+        assert_eq!(parse_read(&mut reader1).unwrap().unwrap().header, "@r2/1\n");
+        assert!(parse_read(&mut reader2).unwrap().is_none());
+    }
 }
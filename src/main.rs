@@ -1,11 +1,18 @@
-use clap;
 use clap::{App, Arg, ArgMatches};
-use fastq_pair::Result;
+use failure::ResultExt;
+use fastq_pair::multiqc::ContentFormat;
+use fastq_pair::verify::verify;
+use fastq_pair::{Compression, Config, derive_prefix, HeaderKeyExtractor, is_alignment_file, is_stream, OnDuplicate, OutputNames, parse_size, Result};
 
+mod auto;
 mod seek;
+mod seek_iter;
 mod store_read;
 mod iter_both;
-mod io;
+mod partition;
+mod stream;
+#[cfg(test)]
+mod testing;
 
 fn cli() -> ArgMatches<'static> {
     let matches = App::new("fastq_pair")
@@ -17,69 +24,693 @@ fn cli() -> ArgMatches<'static> {
                 .short("1")
                 .long("read1")
                 .value_name("PATH")
-                .required(true)
-                .help("Path to Read1 FASTQ")
+                .required_unless("interleaved")
+                .help("Path to Read1 FASTQ or FASTA, or an unaligned SAM/BAM holding both mates; '-' reads stdin")
                 .takes_value(true))
         .arg(
             Arg::with_name("r2")
                 .short("2")
                 .long("read2")
                 .value_name("PATH")
-                .required(true)
-                .help("Path to Read1 FASTQ")
+                .required(false)
+                .help("Path to Read2 FASTQ or FASTA; defaults to --read1 for SAM/BAM or stdin input")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("interleaved")
+                .long("interleaved")
+                .value_name("PATH")
+                .required(false)
+                .conflicts_with_all(&["r1", "r2"])
+                .help("Path to an interleaved FASTQ holding both mates, split by their /1 and /2 style header suffixes")
                 .takes_value(true))
         .arg(
             Arg::with_name("method")
                 .required(false)
                 .takes_value(true)
-                .possible_values(&["store", "seek", "iter"])
-                .default_value("store"))
+                .possible_values(METHOD_NAMES)
+                .help("Pairing method, store unless given; stream pairs inputs in the same order without holding reads, falling back to iter at \
+                       the first mismatch, auto samples the inputs and picks one that fits --memory-budget, and verify checks that --read1 and --read2 are already paired and writes nothing"))
+        .arg(
+            Arg::with_name("method_option")
+                .long("method")
+                .value_name("METHOD")
+                .required(false)
+                .takes_value(true)
+                .possible_values(METHOD_NAMES)
+                .conflicts_with("method")
+                .help("Pairing method, as the positional METHOD"))
+        .arg(
+            Arg::with_name("memory_budget")
+                .long("memory-budget")
+                .value_name("SIZE")
+                .required(false)
+                .takes_value(true)
+                .help("Memory the auto method may plan to use, e.g. 8G; defaults to the available memory"))
+        .arg(
+            Arg::with_name("buckets")
+                .long("buckets")
+                .value_name("N")
+                .required(false)
+                .help("Number of temporary bucket files used by the partition method")
+                .takes_value(true)
+                .default_value("64"))
+        .arg(
+            Arg::with_name("max_memory")
+                .long("max-memory")
+                .value_name("SIZE")
+                .required(false)
+                .takes_value(true)
+                .help("Memory the iter and stream methods may hold reads waiting for their mate in, e.g. 2G; the oldest are spilled to \
+                       disk beyond it, keeping the key and offset of each, outside this limit. Other methods refuse it"))
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .required(false)
+                .takes_value(true)
+                .default_value("1")
+                .help("Threads to use; beyond the first, they parse read 1 and read 2 then write each output"))
+        .arg(
+            Arg::with_name("stats_json")
+                .long("stats-json")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .help("Write records read and written, bytes, pairing rate and wall time to PATH as JSON"))
+        .arg(
+            Arg::with_name("multiqc")
+                .long("multiqc")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .help("Write pairing rate, singletons and method as MultiQC custom content; PATH must end in _mqc.json or _mqc.tsv"))
+        .arg(
+            Arg::with_name("on_duplicate")
+                .long("on-duplicate")
+                .value_name("POLICY")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["error", "first", "last", "singletons"])
+                .default_value("error")
+                .help("What to do with a read whose key was already seen in its input: stop, keep the first or last read, or write later \
+                       ones as singletons. iter, seek-iter and stream refuse last, and need --remember-paired for first and singletons"))
+        .arg(
+            Arg::with_name("remember_paired")
+                .long("remember-paired")
+                .required(false)
+                .help("Have iter, seek-iter and stream catch reads repeating a key already paired, as the other methods do, by \
+                       remembering a hash of every key paired, ~32 bytes each, outside --max-memory. Otherwise they only catch a \
+                       repeat while the earlier read is waiting for its mate, which none are while stream finds inputs in order"))
+        .arg(
+            Arg::with_name("key_regex")
+                .long("key-regex")
+                .value_name("PATTERN")
+                .required(false)
+                .takes_value(true)
+                .help("Regex whose first capture group is the pairing key, matched against headers without '@'"))
+        .arg(
+            Arg::with_name("out_dir")
+                .long("out-dir")
+                .value_name("DIR")
+                .required(false)
+                .takes_value(true)
+                .help("Directory for outputs; defaults to the directory of --read1, or the working directory if it is streamed"))
+        .arg(
+            Arg::with_name("out_r1")
+                .long("out-r1")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .help("Path for paired Read1 output, or '-' for stdout"))
+        .arg(
+            Arg::with_name("out_r2")
+                .long("out-r2")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .help("Path for paired Read2 output, or '-' for stdout"))
+        .arg(
+            Arg::with_name("out_singletons")
+                .long("out-singletons")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .help("Path for singleton output, or '-' for stdout")
+                .conflicts_with("split_singletons"))
+        .arg(
+            Arg::with_name("split_singletons")
+                .long("split-singletons")
+                .required(false)
+                .help("Write unpaired Read1 and Read2 mates to separate singleton files"))
+        .arg(
+            Arg::with_name("out_r1_singletons")
+                .long("out-r1-singletons")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .requires("split_singletons")
+                .help("Path for Read1 singleton output"))
+        .arg(
+            Arg::with_name("out_r2_singletons")
+                .long("out-r2-singletons")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .requires("split_singletons")
+                .help("Path for Read2 singleton output"))
+        .arg(
+            Arg::with_name("prefix")
+                .long("prefix")
+                .value_name("PREFIX")
+                .required(false)
+                .takes_value(true)
+                .help("Prefix output names"))
+        .arg(
+            Arg::with_name("derive_prefix")
+                .long("derive-prefix")
+                .required(false)
+                .conflicts_with("prefix")
+                .help("Prefix output names with the part of the input file names both mates share"))
+        .arg(
+            Arg::with_name("interleave_output")
+                .long("interleave-output")
+                .required(false)
+                .conflicts_with_all(&["out_r1", "out_r2"])
+                .help("Write paired mates alternating into a single file"))
+        .arg(
+            Arg::with_name("out_interleaved")
+                .long("out-interleaved")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .conflicts_with_all(&["out_r1", "out_r2"])
+                .help("Path for interleaved output, or '-' for stdout; implies --interleave-output"))
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .required(false)
+                .help("Overwrite existing output files"))
+        .arg(
+            Arg::with_name("normalize_headers")
+                .long("normalize-headers")
+                .required(false)
+                .help("Rewrite headers as <key>.<mate> and drop '+' line comments instead of copying them verbatim"))
         .arg(
             Arg::with_name("gzip")
                 .long("gzip")
-                .required(false))
+                .required(false)
+                .help("Gzip compress output files"))
+        .arg(
+            Arg::with_name("bgzf")
+                .long("bgzf")
+                .required(false)
+                .conflicts_with("gzip")
+                .help("Block-gzip (BGZF) compress output files for htslib indexing"))
+        .arg(
+            Arg::with_name("level")
+                .long("compression-level")
+                .value_name("0-9")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])
+                .default_value("6")
+                .help("Compression level for --gzip and --bgzf"))
         .get_matches();
 
     matches
 }
 
-fn main() -> Result<()> {
+/// Methods the positional METHOD or --method may name
+const METHOD_NAMES: &[&str] = &["store", "seek", "iter", "seek-iter", "partition", "stream", "auto", "verify"];
+
+/// Methods that write each pair as soon as both mates are found
+const PAIRS_AS_IT_GOES: &[&str] = &["iter", "seek-iter", "stream"];
+
+/// Why `method` can't deal with duplicate keys as `config` asks, if it
+/// can't. Methods pairing as they go can't take back a read already
+/// written in a pair, and unless they remember paired keys they can't
+/// tell a read repeating one from a new read.
+fn duplicate_policy_error(method: &str, config: &Config) -> Option<String> {
+    if !PAIRS_AS_IT_GOES.contains(&method) {
+        return None;
+    }
+    match config.on_duplicate {
+        OnDuplicate::Last => Some(format!("--on-duplicate last only applies to the store, seek and partition methods, not {}", method)),
+        OnDuplicate::First if !config.remember_paired => Some(format!("--on-duplicate first needs --remember-paired with {}", method)),
+        OnDuplicate::Singletons if !config.remember_paired =>
+            Some(format!("--on-duplicate singletons needs --remember-paired with {}", method)),
+        _ => None,
+    }
+}
+
+/// An error's message followed by those of its causes, as shown to the user
+fn error_message(error: &failure::Error) -> String {
+    error.iter_chain().map(|cause| cause.to_string()).collect::<Vec<_>>().join(": ")
+}
+
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("Error: {}", error_message(&e));
+        std::process::exit(1);
+    }
+}
+
+fn try_main() -> Result<()> {
     // Argument parsing
     // Unwrap is safe here due to all arguments being either required or having defaults
     let matches = cli();
-    let mut r1_path = matches.value_of("r1").unwrap().to_string();
-    let mut r2_path = matches.value_of("r2").unwrap().to_string();
-    let method = matches.value_of("method").unwrap();
-    let gzip = matches.is_present("gzip");
+    let interleaved = matches.is_present("interleaved");
+    let r1_path = matches.value_of("r1").or_else(|| matches.value_of("interleaved")).unwrap();
+    // SAM/BAM inputs hold both mates, split by their READ1/READ2 flags
+    let r2_path = match matches.value_of("r2") {
+        Some(path) => path,
+        None if interleaved || is_stream(r1_path)? || is_alignment_file(r1_path)? => r1_path,
+        None => failure::bail!("--read2 is required unless --read1 is a SAM/BAM file"),
+    };
+    let buckets: usize = matches.value_of("buckets").unwrap().parse()?;
+    // Checked up front so a bad name doesn't waste a run
+    let multiqc = match matches.value_of("multiqc") {
+        Some(path) => Some((path, ContentFormat::for_path(path)?)),
+        None => None,
+    };
+    let max_memory = matches.value_of("max_memory").map(parse_size).transpose()?;
+    let compression = if matches.is_present("gzip") {
+        Compression::Gzip
+    } else if matches.is_present("bgzf") {
+        Compression::Bgzf
+    } else {
+        Compression::Plain
+    };
+    let threads: usize = matches.value_of("threads").unwrap().parse()?;
+    if threads == 0 {
+        failure::bail!("Number of threads must be at least 1");
+    }
+    let config = Config {
+        compression,
+        level: matches.value_of("level").unwrap().parse()?,
+        key_extractor: matches.value_of("key_regex").map(HeaderKeyExtractor::from_pattern).transpose()?,
+        normalize_headers: matches.is_present("normalize_headers"),
+        interleaved,
+        outputs: OutputNames {
+            dir: matches.value_of("out_dir").map(String::from),
+            prefix: match matches.value_of("prefix") {
+                Some(prefix) => Some(prefix.to_string()),
+                None if matches.is_present("derive_prefix") => Some(derive_prefix(r1_path, r2_path)),
+                None => None,
+            },
+            r1: matches.value_of("out_r1").map(String::from),
+            r2: matches.value_of("out_r2").map(String::from),
+            singletons: matches.value_of("out_singletons").map(String::from),
+            split_singletons: matches.is_present("split_singletons"),
+            r1_singletons: matches.value_of("out_r1_singletons").map(String::from),
+            r2_singletons: matches.value_of("out_r2_singletons").map(String::from),
+            interleave: matches.is_present("interleave_output") || matches.is_present("out_interleaved"),
+            interleaved: matches.value_of("out_interleaved").map(String::from),
+            force: matches.is_present("force"),
+        },
+        threads,
+        on_duplicate: match matches.value_of("on_duplicate").unwrap() {
+            "error" => OnDuplicate::Error,
+            "first" => OnDuplicate::First,
+            "last" => OnDuplicate::Last,
+            "singletons" => OnDuplicate::Singletons,
+            _ => unreachable!(),
+        },
+        remember_paired: matches.is_present("remember_paired"),
+    };
 
-    // Check input and uncompress if necessary
-    r1_path = io::convert_to_fastq(&r1_path)?;
-    r2_path = io::convert_to_fastq(&r2_path)?;
+    // Only iter, and stream once it falls back to iter, cap their memory
+    let requested = matches.value_of("method_option").or_else(|| matches.value_of("method")).unwrap_or("store");
+    let capped = |method: &str| max_memory.is_none() || method == "iter" || method == "stream";
+    if requested != "auto" && !capped(requested) {
+        failure::bail!("--max-memory only applies to the iter and stream methods, not {}", requested);
+    }
+    if let Some(reason) = duplicate_policy_error(requested, &config) {
+        failure::bail!(reason);
+    }
+    let method = match requested {
+        "verify" => {
+            let pairs = verify(r1_path, r2_path, &config)?;
+            eprintln!("{} pairs verified", pairs);
+            return Ok(());
+        }
+        "auto" => {
+            let budget = match matches.value_of("memory_budget") {
+                Some(size) => parse_size(size)?,
+                None => auto::default_budget(),
+            };
+            let choice = auto::choose_method(r1_path, r2_path, &config, budget, buckets)?;
+            eprintln!("Using {}: {}", choice.method, choice.reason);
+            if !capped(choice.method) {
+                failure::bail!("--max-memory only applies to the iter and stream methods; choose one instead of auto");
+            }
+            choice.method
+        }
+        method => method,
+    };
 
     // Pair fastqs
-    let output;
-    match method {
-        "store" => {
-            output = store_read::pair_fastqs(&r1_path, &r2_path)?;
+    let output = match method {
+        "store" => store_read::pair_fastqs(r1_path, r2_path, &config)?,
+        "seek" => seek::pair_fastqs(r1_path, r2_path, &config)?,
+        "iter" => iter_both::pair_fastqs(r1_path, r2_path, &config, max_memory)?,
+        "seek-iter" => seek_iter::pair_fastqs(r1_path, r2_path, &config)?,
+        "partition" => partition::pair_fastqs(r1_path, r2_path, &config, buckets)?,
+        "stream" => stream::pair_fastqs(r1_path, r2_path, &config, max_memory)?,
+        _ => unreachable!(),
+    };
+    let counts = output.stats.counts;
+    eprintln!("{} pairs, {} R1 singletons, {} R2 singletons, {} duplicates",
+              counts.pairs, counts.r1_singletons, counts.r2_singletons, counts.duplicates);
+    if let Some(path) = matches.value_of("stats_json") {
+        std::fs::write(path, output.stats.to_json()).context(format!("Can't write stats to {}", path))?;
+    }
+    if let Some((path, format)) = multiqc {
+        let sample = config.outputs.prefix.clone().unwrap_or_else(|| derive_prefix(r1_path, r2_path));
+        std::fs::write(path, format.render(&sample, method, &output.stats)).context(format!("Can't write MultiQC output to {}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{gzipped_inputs, interleaved_input, records, shuffled_inputs, sorted_records, wrapped_inputs};
+    use fastq_pair::{Counts, Output, ParseError, ParseErrorKind};
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Every pairing method, store first as the others are checked against it
+    const METHODS: &[&str] = &["store", "seek", "iter", "seek-iter", "partition", "stream"];
+
+    fn run(method: &str, r1_path: &str, r2_path: &str, config: &Config) -> Result<Output> {
+        match method {
+            "store" => store_read::pair_fastqs(r1_path, r2_path, config),
+            "seek" => seek::pair_fastqs(r1_path, r2_path, config),
+            "iter" => iter_both::pair_fastqs(r1_path, r2_path, config, None),
+            "seek-iter" => seek_iter::pair_fastqs(r1_path, r2_path, config),
+            "partition" => partition::pair_fastqs(r1_path, r2_path, config, 3),
+            "stream" => stream::pair_fastqs(r1_path, r2_path, config, None),
+            _ => unreachable!(),
         }
-        "seek" => {
-            output = seek::pair_fastqs(&r1_path, &r2_path)?;
+    }
+
+    /// Sorted R1, R2, singleton and R2 singleton records written by a
+    /// pairing method, along with its counts
+    fn pair_with(method: &str, config: &Config) -> ([Vec<String>; 4], Counts) {
+        let tmpdir = tempdir().unwrap();
+        let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+        let output = run(method, &r1_path, &r2_path, config).unwrap();
+        ([sorted_records(Some(&output.r1_out_path)),
+          sorted_records(output.r2_out_path.as_deref()),
+          sorted_records(output.singleton_path.as_deref()),
+          sorted_records(output.r2_singleton_path.as_deref())],
+         output.stats.counts)
+    }
+
+    #[test]
+    fn test_methods_agree() {
+        for normalize_headers in &[false, true] {
+            let config = Config { normalize_headers: *normalize_headers, ..Config::default() };
+            let expected = pair_with("store", &config);
+            assert_eq!(expected.1, Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 0 });
+            assert_eq!(expected.0[2].len(), 2);
+            assert_eq!(expected.0[0][0].starts_with("@SRR3380692.1.1 1 length=101\n"), !normalize_headers);
+            for method in &METHODS[1..] {
+                assert_eq!(pair_with(method, &config), expected, "{} differs from store", method);
+            }
         }
-        "iter" => {
-            output = iter_both::pair_fastqs(&r1_path, &r2_path)?;
+    }
+
+    #[test]
+    fn test_split_singletons() {
+        let outputs = OutputNames { split_singletons: true, ..OutputNames::default() };
+        let config = Config { outputs, ..Config::default() };
+        for method in METHODS {
+            let (records, counts) = pair_with(method, &config);
+            assert_eq!(counts, Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 0 });
+            assert!(records[2][0].starts_with("@SRR3380692.9.1 "), "{} wrote R2 to R1 singletons", method);
+            assert!(records[3][0].starts_with("@SRR3380692.5.2 "), "{} wrote R1 to R2 singletons", method);
         }
-        _ => {
-            unreachable!();
+    }
+
+    #[test]
+    fn test_error_message() {
+        let err = verify("data/ncbi_1_shuffled.fastq", "data/ncbi_2_shuffled.fastq", &Config::default()).unwrap_err();
+        assert!(error_message(&err).starts_with("data/ncbi_1_shuffled.fastq: record 1 at byte 0 has key @SRR3380692.3,"));
+        let err = failure::Error::from(err.context("Can't verify"));
+        assert!(error_message(&err).starts_with("Can't verify: data/ncbi_1_shuffled.fastq: record 1"));
+    }
+
+    #[test]
+    fn test_outputs_verify() {
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+            assert!(verify(&r1_path, &r2_path, &Config::default()).is_err());
+            let output = run(method, &r1_path, &r2_path, &Config::default()).unwrap();
+            let r2_out_path = output.r2_out_path.unwrap();
+            assert_eq!(verify(&output.r1_out_path, &r2_out_path, &Config::default()).unwrap(), 4, "{} output is not paired", method);
         }
     }
 
-    // Compress output if specified
-    if gzip == true {
-        io::gzip(&output.r1_out_path)?;
-        io::gzip(&output.r2_out_path)?;
-        if let Some(singleton_path) = &output.singleton_path {
-            io::gzip(singleton_path)?;
+    #[test]
+    fn test_interleaved_input() {
+        let expected = pair_with("store", &Config::default());
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let path = interleaved_input(tmpdir.path());
+            let config = Config { interleaved: true, ..Config::default() };
+            let output = run(method, &path, &path, &config).unwrap();
+            assert_eq!(output.stats.counts, expected.1, "{} miscounted interleaved input", method);
+            assert_eq!(sorted_records(Some(&output.r1_out_path)), expected.0[0]);
+            assert_eq!(sorted_records(output.r2_out_path.as_deref()), expected.0[1]);
+            assert_eq!(sorted_records(output.singleton_path.as_deref()), expected.0[2]);
+        }
+    }
+
+    #[test]
+    fn test_interleaved_output() {
+        let outputs = OutputNames { interleave: true, ..OutputNames::default() };
+        let config = Config { outputs, ..Config::default() };
+        let expected = pair_with("store", &Config::default());
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+            let output = run(method, &r1_path, &r2_path, &config).unwrap();
+            assert!(output.r1_out_path.ends_with("Interleaved.fastq"));
+            assert_eq!(output.r2_out_path, None);
+            // Mates alternate
+            let records = records(&output.r1_out_path);
+            assert_eq!(records.len(), 8);
+            for (i, pair) in records.chunks(2).enumerate() {
+                assert_eq!(pair[0].0, pair[1].0, "{} split pair {}", method, i);
+            }
+            let mut paired = [expected.0[0].clone(), expected.0[1].clone()].concat();
+            paired.sort();
+            assert_eq!(sorted_records(Some(&output.r1_out_path)), paired);
+        }
+    }
+
+    #[test]
+    fn test_wrapped_input() {
+        let expected = pair_with("store", &Config::default());
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, r2_path) = wrapped_inputs(tmpdir.path(), false);
+            let output = run(method, &r1_path, &r2_path, &Config::default()).unwrap();
+            assert_eq!(output.stats.counts, expected.1, "{} miscounted wrapped input", method);
+            // Written back unwrapped
+            assert_eq!(sorted_records(Some(&output.r1_out_path)), expected.0[0]);
+            assert_eq!(sorted_records(output.r2_out_path.as_deref()), expected.0[1]);
+            assert_eq!(sorted_records(output.singleton_path.as_deref()), expected.0[2]);
+        }
+    }
+
+    #[test]
+    fn test_gzipped_input() {
+        let expected = pair_with("store", &Config::default());
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, r2_path) = gzipped_inputs(tmpdir.path());
+            let output = run(method, &r1_path, &r2_path, &Config::default()).unwrap();
+            assert_eq!(output.stats.counts, expected.1, "{} miscounted gzipped input", method);
+            assert_eq!(sorted_records(Some(&output.r1_out_path)), expected.0[0]);
+            assert_eq!(sorted_records(output.r2_out_path.as_deref()), expected.0[1]);
+            assert_eq!(sorted_records(output.singleton_path.as_deref()), expected.0[2]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_piped_input() {
+        let expected = pair_with("store", &Config::default());
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, _) = shuffled_inputs(tmpdir.path());
+            // Read 2 comes through a named pipe, as it would from stdin
+            let pipe = tmpdir.path().join("ncbi_2_shuffled.fastq.pipe");
+            assert!(std::process::Command::new("mkfifo").arg(&pipe).status().unwrap().success());
+            let pipe = pipe.to_str().unwrap().to_string();
+            let writer = {
+                let pipe = pipe.clone();
+                std::thread::spawn(move || std::fs::write(pipe, include_str!("../data/ncbi_2_shuffled.fastq")).unwrap())
+            };
+            let output = run(method, &r1_path, &pipe, &Config::default()).unwrap();
+            writer.join().unwrap();
+            assert_eq!(output.stats.counts, expected.1, "{} miscounted piped input", method);
+            assert_eq!(sorted_records(output.r2_out_path.as_deref()), expected.0[1]);
+        }
+    }
+
+    #[test]
+    fn test_fasta_input() {
+        let (expected, counts) = pair_with("store", &Config::default());
+        let as_fasta = |records: &Vec<String>| {
+            let mut fasta: Vec<String> = records.iter()
+                .map(|r| format!(">{}", r[1..].lines().take(2).map(|l| format!("{}\n", l)).collect::<String>()))
+                .collect();
+            fasta.sort();
+            fasta
+        };
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, r2_path) = wrapped_inputs(tmpdir.path(), true);
+            let output = run(method, &r1_path, &r2_path, &Config::default()).unwrap();
+            assert_eq!(output.stats.counts, counts, "{} miscounted FASTA input", method);
+            assert!(output.r1_out_path.ends_with("R1_paired.fasta"));
+            assert_eq!(sorted_records(Some(&output.r1_out_path)), as_fasta(&expected[0]));
+            assert_eq!(sorted_records(output.r2_out_path.as_deref()), as_fasta(&expected[1]));
+            assert_eq!(sorted_records(output.singleton_path.as_deref()), as_fasta(&expected[2]));
+        }
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+            // Cut the last record short
+            let fastq = std::fs::read_to_string(&r2_path).unwrap();
+            std::fs::write(&r2_path, &fastq[..fastq.len() - 20]).unwrap();
+            let err = run(method, &r1_path, &r2_path, &Config::default()).err()
+                .unwrap_or_else(|| panic!("{} accepted a truncated input", method));
+            let err = err.downcast::<ParseError>().unwrap();
+            assert_eq!(err.record, Some(5), "{} misreported the record", method);
+            assert!(matches!(err.kind, ParseErrorKind::LengthMismatch { .. }));
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        let outputs = OutputNames { split_singletons: true, ..OutputNames::default() };
+        for compression in &[Compression::Plain, Compression::Bgzf] {
+            for method in METHODS {
+                let mut written = Vec::new();
+                for threads in &[1, 2, 8] {
+                    let tmpdir = tempdir().unwrap();
+                    let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+                    let config = Config { compression: *compression, outputs: outputs.clone(), threads: *threads, ..Config::default() };
+                    let output = run(method, &r1_path, &r2_path, &config).unwrap();
+                    let paths = [Some(output.r1_out_path), output.r2_out_path, output.singleton_path, output.r2_singleton_path];
+                    let bytes: Vec<Vec<u8>> = paths.iter().flatten().map(|path| std::fs::read(path).unwrap()).collect();
+                    assert_eq!(bytes.len(), 4);
+                    written.push(bytes);
+                }
+                assert!(written.iter().all(|bytes| bytes == &written[0]), "{} output depends on the thread count", method);
+            }
+        }
+        // Parse errors come through from the parser threads
+        let tmpdir = tempdir().unwrap();
+        let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+        let fastq = std::fs::read_to_string(&r2_path).unwrap();
+        std::fs::write(&r2_path, &fastq[..fastq.len() - 20]).unwrap();
+        let config = Config { threads: 8, ..Config::default() };
+        let err = run("iter", &r1_path, &r2_path, &config).err().unwrap().downcast::<ParseError>().unwrap();
+        assert_eq!(err.record, Some(5));
+    }
+
+    #[test]
+    fn test_stats() {
+        for method in METHODS {
+            let tmpdir = tempdir().unwrap();
+            let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+            // Repeat the last record, the R1 singleton
+            let fastq = std::fs::read_to_string(&r1_path).unwrap();
+            let lines: Vec<&str> = fastq.lines().collect();
+            std::fs::write(&r1_path, format!("{}{}\n", fastq, lines[lines.len() - 4..].join("\n"))).unwrap();
+            let config = Config { on_duplicate: OnDuplicate::First, ..Config::default() };
+            let output = run(method, &r1_path, &r2_path, &config).unwrap();
+            let stats = output.stats;
+            assert_eq!((stats.r1_records, stats.r2_records), (6, 5), "{} miscounted records", method);
+            assert_eq!(stats.counts, Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 1 }, "{} miscounted", method);
+            let input_size = std::fs::metadata(&r1_path).unwrap().len() + std::fs::metadata(&r2_path).unwrap().len();
+            assert_eq!(stats.bytes_read, input_size);
+            let paths = [Some(output.r1_out_path), output.r2_out_path, output.singleton_path];
+            let output_size: u64 = paths.iter().flatten().map(|path| std::fs::metadata(path).unwrap().len()).sum();
+            assert_eq!(stats.bytes_written, output_size);
+            assert_eq!(stats.pairing_rate(), 8.0 / 11.0);
+            let json = stats.to_json();
+            assert!(json.starts_with("{\n  \"r1_records\": 6,\n"), "{}", json);
+            assert!(json.contains("\"pairs\": 4,") && json.contains("\"pairing_rate\": 0.727273,"));
+        }
+    }
+
+    /// Copies of the shuffled inputs in `dir` with a read of each repeated
+    /// at the end, both having mates: the first R1 read, with its sequence
+    /// lowercased so it can be told apart, and the last R2 read
+    fn duplicated_inputs(dir: &std::path::Path) -> (String, String) {
+        let (r1_path, r2_path) = shuffled_inputs(dir);
+        for (path, record) in &[(&r1_path, 0), (&r2_path, 4)] {
+            let fastq = std::fs::read_to_string(path).unwrap();
+            let mut lines: Vec<String> = fastq.lines().skip(record * 4).take(4).map(String::from).collect();
+            if *record == 0 {
+                lines[1] = lines[1].to_lowercase();
+            }
+            std::fs::write(path, format!("{}{}\n", fastq, lines.join("\n"))).unwrap();
+        }
+        (r1_path, r2_path)
+    }
+
+    #[test]
+    fn test_on_duplicate() {
+        let tmpdir = tempdir().unwrap();
+        let (r1_path, r2_path) = duplicated_inputs(tmpdir.path());
+        assert!(std::fs::read_to_string(&r2_path).unwrap().contains("@SRR3380692.5.2"));
+        let has_copy = |path: &str| records(path).iter().any(|(_, seq, _)| seq.chars().any(|c| c.is_ascii_lowercase()));
+        for method in METHODS {
+            let policy = |on_duplicate| Config { on_duplicate, remember_paired: true,
+                                                 outputs: OutputNames { force: true, ..OutputNames::default() }, ..Config::default() };
+            let err = run(method, &r1_path, &r2_path, &policy(OnDuplicate::Error)).err()
+                .unwrap_or_else(|| panic!("{} accepted a duplicate", method));
+            assert!(err.to_string().contains("appears more than once"), "{}", err);
+            // Without remembering paired keys, methods pairing as they go
+            // take copies of reads already paired for new reads
+            let pairs_as_it_goes = PAIRS_AS_IT_GOES.contains(method);
+            let bounded = Config { remember_paired: false, ..policy(OnDuplicate::Error) };
+            match run(method, &r1_path, &r2_path, &bounded) {
+                Ok(output) => {
+                    assert!(pairs_as_it_goes, "{} accepted a duplicate", method);
+                    assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 2, r2_singletons: 2, duplicates: 0 });
+                }
+                Err(_) => assert!(!pairs_as_it_goes, "{} caught a copy of a paired read", method),
+            }
+            let output = run(method, &r1_path, &r2_path, &policy(OnDuplicate::First)).unwrap();
+            assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 2 }, "{} miscounted", method);
+            assert!(!has_copy(&output.r1_out_path));
+            let output = run(method, &r1_path, &r2_path, &policy(OnDuplicate::Singletons)).unwrap();
+            assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 2, r2_singletons: 2, duplicates: 2 }, "{} miscounted", method);
+            assert!(has_copy(output.singleton_path.as_deref().unwrap()));
+            // Methods pairing as they go have already written the original
+            // by the time its copy comes, so can't keep the last
+            assert_eq!(duplicate_policy_error(method, &policy(OnDuplicate::Last)).is_some(), pairs_as_it_goes);
+            assert!(duplicate_policy_error(method, &bounded).is_none());
+            assert_eq!(duplicate_policy_error(method, &Config { remember_paired: false, ..policy(OnDuplicate::First) }).is_some(),
+                       pairs_as_it_goes);
+            if !pairs_as_it_goes {
+                let output = run(method, &r1_path, &r2_path, &policy(OnDuplicate::Last)).unwrap();
+                assert_eq!(output.stats.counts.duplicates, 2);
+                assert!(has_copy(&output.r1_out_path), "{} kept the wrong read", method);
+            }
         }
     }
-    Ok(())
 }
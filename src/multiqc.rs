@@ -0,0 +1,126 @@
+//! MultiQC custom content, so that pairing shows up in QC reports next to
+//! the other steps of a pipeline. MultiQC picks up files named `*_mqc.json`
+//! or `*_mqc.tsv`; see https://multiqc.info/docs/custom_content/
+
+use crate::{PairingStats, Result};
+
+const ID: &str = "fastq_pair";
+const DESCRIPTION: &str = "Reads paired by fastq_pair, and those left without a mate";
+
+/// Which kind of custom content file to write, going by its name
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentFormat {
+    Json,
+    Tsv,
+}
+
+impl ContentFormat {
+    pub fn for_path(path: &str) -> Result<ContentFormat> {
+        if path.ends_with("_mqc.json") {
+            Ok(ContentFormat::Json)
+        } else if path.ends_with("_mqc.tsv") {
+            Ok(ContentFormat::Tsv)
+        } else {
+            failure::bail!("MultiQC output {} must end in _mqc.json or _mqc.tsv to be found by MultiQC", path)
+        }
+    }
+
+    /// A one row table of how `sample` was paired
+    pub fn render(self, sample: &str, method: &str, stats: &PairingStats) -> String {
+        match self {
+            ContentFormat::Json => json(sample, method, stats),
+            ContentFormat::Tsv => tsv(sample, method, stats),
+        }
+    }
+}
+
+/// Table columns and their values, the method being the only string
+fn columns(method: &str, stats: &PairingStats) -> [(&'static str, String); 5] {
+    [
+        ("method", method.to_string()),
+        ("pairs", stats.counts.pairs.to_string()),
+        ("r1_singletons", stats.counts.r1_singletons.to_string()),
+        ("r2_singletons", stats.counts.r2_singletons.to_string()),
+        ("pairing_rate", format!("{:.2}", stats.pairing_rate() * 100.0)),
+    ]
+}
+
+/// Quote a string for JSON
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn json(sample: &str, method: &str, stats: &PairingStats) -> String {
+    let row: Vec<String> = columns(method, stats).iter()
+        .map(|(name, value)| match *name {
+            "method" => format!("\"{}\": {}", name, quote(value)),
+            _ => format!("\"{}\": {}", name, value),
+        })
+        .collect();
+    format!(r#"{{
+  "id": "{id}",
+  "section_name": "{id}",
+  "description": {description},
+  "plot_type": "table",
+  "pconfig": {{"id": "{id}_table", "title": "{id}"}},
+  "headers": {{"pairing_rate": {{"title": "% Paired", "suffix": "%", "min": 0, "max": 100}}}},
+  "data": {{
+    {sample}: {{{row}}}
+  }}
+}}
+"#, id = ID, description = quote(DESCRIPTION), sample = quote(sample), row = row.join(", "))
+}
+
+fn tsv(sample: &str, method: &str, stats: &PairingStats) -> String {
+    let columns = columns(method, stats);
+    let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+    let values: Vec<&str> = columns.iter().map(|(_, value)| value.as_str()).collect();
+    format!("# id: '{id}'\n# section_name: '{id}'\n# description: '{description}'\n# plot_type: 'table'\n\
+             Sample\t{names}\n{sample}\t{values}\n",
+            id = ID, description = DESCRIPTION, names = names.join("\t"), sample = sample, values = values.join("\t"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Counts;
+    use std::time::Duration;
+    use super::*;
+
+    fn stats() -> PairingStats {
+        PairingStats {
+            r1_records: 5,
+            r2_records: 5,
+            counts: Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 0 },
+            bytes_read: 2620,
+            bytes_written: 2620,
+            wall_time: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn test_content_format() {
+        assert_eq!(ContentFormat::for_path("out/sample_mqc.json").unwrap(), ContentFormat::Json);
+        assert_eq!(ContentFormat::for_path("sample_mqc.tsv").unwrap(), ContentFormat::Tsv);
+        assert!(ContentFormat::for_path("sample.json").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let json = ContentFormat::Json.render("sample \"1\"", "store", &stats());
+        assert!(json.contains(r#""sample \"1\"": {"method": "store", "pairs": 4, "r1_singletons": 1, "r2_singletons": 1, "pairing_rate": 80.00}"#), "{}", json);
+        assert!(json.contains(r#""plot_type": "table""#));
+        let tsv = ContentFormat::Tsv.render("sample", "iter", &stats());
+        assert!(tsv.starts_with("# id: 'fastq_pair'\n"));
+        assert!(tsv.ends_with("Sample\tmethod\tpairs\tr1_singletons\tr2_singletons\tpairing_rate\nsample\titer\t4\t1\t1\t80.00\n"), "{}", tsv);
+    }
+}
@@ -0,0 +1,107 @@
+use fastq_pair::{create_io, Config, FastqReader, HeaderKeyExtractor, IO_BUFFER_SIZE, Keyed, Output, Records};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use super::Result;
+use super::store_read::{index_read, write_pairs};
+
+/// Assign a header key to a bucket. `DefaultHasher::new` uses fixed keys, so
+/// mates from both files always land in the same bucket.
+fn bucket_of(header: &str, buckets: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    header.hash(&mut hasher);
+    (hasher.finish() % buckets as u64) as usize
+}
+
+/// Split a FASTQ into `buckets` files within `dir`, keyed on the unique header
+/// component. Returns the bucket paths in bucket order.
+fn partition(input: &mut Records<impl BufRead>, dir: &Path, name: &str, buckets: usize) -> Result<Vec<PathBuf>> {
+    let paths: Vec<PathBuf> = (0..buckets)
+        .map(|i| dir.join(format!("{}_{}.fastq", name, i)))
+        .collect();
+    let mut writers = Vec::with_capacity(buckets);
+    for path in &paths {
+        writers.push(BufWriter::new(File::create(path)?));
+    }
+    while let Some(Keyed { key: header, read, .. }) = input.next_record()? {
+        read.write_to(&mut writers[bucket_of(&header, buckets)])?;
+    }
+    for writer in &mut writers {
+        writer.flush()?;
+    }
+    Ok(paths)
+}
+
+fn open_bucket(path: &Path, extractor: &HeaderKeyExtractor) -> Result<Records<BufReader<File>>> {
+    let reader = FastqReader::new(BufReader::with_capacity(IO_BUFFER_SIZE, File::open(path)?), path.to_str().unwrap_or("bucket"));
+    Ok(Records::new(reader, extractor.clone()))
+}
+
+/// Pair FASTQ files that don't fit in memory. Both inputs are hash-partitioned
+/// on disk by header so mates share a bucket, then each bucket pair is paired
+/// with the "store_read" method. Peak memory is roughly one R1 bucket.
+pub fn pair_fastqs(r1_path: &str, r2_path: &str, config: &Config, buckets: usize) -> Result<Output> {
+    if buckets == 0 {
+        failure::bail!("Number of buckets must be at least 1");
+    }
+    let mut io = create_io(r1_path, r2_path, config)?;
+    // Keep buckets next to the outputs; removed when `tmpdir` is dropped
+    let tmpdir = tempfile::Builder::new().prefix(".fastq_pair").tempdir_in(io.temp_dir())?;
+    let buckets1 = partition(&mut io.in_read1, tmpdir.path(), "R1", buckets)?;
+    let buckets2 = partition(&mut io.in_read2, tmpdir.path(), "R2", buckets)?;
+    for (bucket1, bucket2) in buckets1.iter().zip(buckets2.iter()) {
+        // Reads sharing a key share a bucket, so duplicates are found within one
+        let map = index_read(&mut open_bucket(bucket1, &io.key1)?, &mut io.out)?;
+        let mut in_read2 = open_bucket(bucket2, &io.key2)?;
+        write_pairs(map, &mut in_read2, &mut io.out)?;
+        // Free disk space as soon as a bucket is done
+        fs::remove_file(bucket1)?;
+        fs::remove_file(bucket2)?;
+    }
+    tmpdir.close()?;
+    io.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use fastq_pair::{open_fastq, parse_header, parse_read};
+    use std::fs::copy;
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_bucket_of() {
+        assert_eq!(bucket_of("@SRR3380692.1", 1), 0);
+        assert_eq!(bucket_of("@SRR3380692.1", 16), bucket_of("@SRR3380692.1", 16));
+        assert!(bucket_of("@SRR3380692.1", 16) < 16);
+    }
+
+    #[test]
+    fn test_pair_fastqs() {
+        let tmpdir = tempdir().unwrap();
+        let tmppath = tmpdir.path();
+        let r1_path = tmppath.join("ncbi_1_shuffled.fastq");
+        let r2_path = tmppath.join("ncbi_2_shuffled.fastq");
+        copy("data/ncbi_1_shuffled.fastq", &r1_path).unwrap();
+        copy("data/ncbi_2_shuffled.fastq", &r2_path).unwrap();
+        let output = pair_fastqs(r1_path.to_str().unwrap(), r2_path.to_str().unwrap(), &Config::default(), 3).unwrap();
+        // Assert each header in each file matches
+        let mut reader1 = open_fastq(&output.r1_out_path).unwrap();
+        let mut reader2 = open_fastq(&output.r2_out_path.unwrap()).unwrap();
+        for _ in 0..4 {
+            let r1 = parse_read(&mut reader1).unwrap().unwrap();
+            let r2 = parse_read(&mut reader2).unwrap().unwrap();
+            assert_eq!(parse_header(&r1.header).unwrap(),
+                       parse_header(&r2.header).unwrap());
+        }
+        assert!(parse_read(&mut reader1).unwrap().is_none());
+        let mut singletons = open_fastq(&output.singleton_path.unwrap()).unwrap();
+        assert!(parse_read(&mut singletons).unwrap().is_some());
+        assert!(parse_read(&mut singletons).unwrap().is_some());
+        assert!(parse_read(&mut singletons).unwrap().is_none());
+        // Only inputs and outputs remain; bucket files are cleaned up
+        assert_eq!(fs::read_dir(tmppath).unwrap().count(), 5);
+    }
+}
@@ -0,0 +1,255 @@
+//! Runs the stages of pairing on threads of their own: parsing each input
+//! and extracting its keys, and serializing and compressing each output.
+//! Stages hand work to each other in order over bounded channels, so the
+//! outputs are the same however many threads are used.
+
+use crate::{FastqReader, HeaderKeyExtractor, RecordRef, Result, Sink};
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::str;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Records parsed before they're handed on as a batch
+const BATCH_RECORDS: usize = 1024;
+
+/// Batches, or output buffers, in flight between two stages
+const CHANNEL_BOUND: usize = 8;
+
+/// A record along with its pairing key and where it starts in its input
+pub struct Keyed<'a> {
+    pub key: String,
+    pub read: RecordRef<'a>,
+    pub offset: u64,
+}
+
+/// Parsed records packed into one buffer
+#[derive(Default)]
+struct Batch {
+    data: Vec<u8>,
+    /// Key and offset of each record, and where its header, sequence, `+`
+    /// line and quality end within `data`
+    records: Vec<(String, u64, [usize; 4])>,
+    /// Offset within the input just past the last record
+    end: u64,
+}
+
+impl Batch {
+    fn push(&mut self, key: String, offset: u64, read: &RecordRef) {
+        let mut ends = [0; 4];
+        for (end, line) in ends.iter_mut().zip(&[read.header.as_bytes(), read.seq, read.separator, read.qscore]) {
+            self.data.extend_from_slice(line);
+            *end = self.data.len();
+        }
+        self.records.push((key, offset, ends));
+    }
+}
+
+/// Fill `batch` from `reader`, returning whether there may be more records
+fn fill_batch(reader: &mut FastqReader<impl BufRead>, extractor: &HeaderKeyExtractor, batch: &mut Batch) -> Result<bool> {
+    for _ in 0..BATCH_RECORDS {
+        let offset = reader.offset();
+        match reader.next_record()? {
+            Some(read) => batch.push(extractor.key(read.header)?, offset, &read),
+            None => {
+                batch.end = reader.offset();
+                return Ok(false);
+            }
+        }
+    }
+    batch.end = reader.offset();
+    Ok(true)
+}
+
+/// Parse all of `reader` into batches, ending with None or the first error.
+/// Returns early if the receiving end hangs up.
+fn parse(mut reader: FastqReader<impl BufRead>, extractor: HeaderKeyExtractor,
+         sender: SyncSender<Result<Option<Batch>>>, recycled: Receiver<Batch>) {
+    loop {
+        let mut batch = recycled.try_recv().unwrap_or_default();
+        batch.data.clear();
+        batch.records.clear();
+        let result = fill_batch(&mut reader, &extractor, &mut batch);
+        // Records parsed before an error are still handed on, as is the
+        // offset of the end of the input
+        let last = !matches!(result, Ok(true));
+        if (last || !batch.records.is_empty()) && sender.send(Ok(Some(batch))).is_err() {
+            return;
+        }
+        match result {
+            Ok(true) => {}
+            Ok(false) => return drop(sender.send(Ok(None))),
+            Err(e) => return drop(sender.send(Err(e))),
+        }
+    }
+}
+
+/// Batches arriving from a parser thread
+struct Parsed {
+    receiver: Receiver<Result<Option<Batch>>>,
+    /// Finished batches go back to the parser to reuse their buffers
+    recycle: Sender<Batch>,
+    batch: Batch,
+    /// Next record of `batch`, and where it starts in `batch.data`
+    next: usize,
+    start: usize,
+    done: bool,
+}
+
+enum Source<R> {
+    Direct(FastqReader<R>, HeaderKeyExtractor),
+    Threaded(Parsed),
+}
+
+/// Keyed records of one input, parsed either as they're asked for or ahead
+/// of time on a thread of their own
+pub struct Records<R> {
+    source: Source<R>,
+    path: String,
+    records: u64,
+}
+
+impl<R: BufRead> Records<R> {
+    /// Parse records of `reader` as they're asked for
+    pub fn new(reader: FastqReader<R>, extractor: HeaderKeyExtractor) -> Records<R> {
+        let path = reader.path().to_string();
+        Records { source: Source::Direct(reader, extractor), path, records: 0 }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Number of records read so far
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Bytes of (decompressed) input parsed so far
+    pub fn bytes(&self) -> u64 {
+        match &self.source {
+            Source::Direct(reader, _) => reader.offset(),
+            Source::Threaded(parsed) => parsed.batch.end,
+        }
+    }
+
+    /// The next record and its key, or None at EOF
+    pub fn next_record(&mut self) -> Result<Option<Keyed<'_>>> {
+        match &mut self.source {
+            Source::Direct(reader, extractor) => {
+                let offset = reader.offset();
+                Ok(match reader.next_record()? {
+                    Some(read) => {
+                        self.records += 1;
+                        Some(Keyed { key: extractor.key(read.header)?, read, offset })
+                    }
+                    None => None,
+                })
+            }
+            Source::Threaded(parsed) => {
+                while parsed.next == parsed.batch.records.len() {
+                    if parsed.done {
+                        return Ok(None);
+                    }
+                    let batch = match parsed.receiver.recv() {
+                        Ok(Ok(Some(batch))) => batch,
+                        Ok(Ok(None)) => {
+                            parsed.done = true;
+                            continue;
+                        }
+                        Ok(Err(e)) => {
+                            parsed.done = true;
+                            return Err(e);
+                        }
+                        Err(_) => failure::bail!("Parsing {} stopped unexpectedly", self.path),
+                    };
+                    let finished = mem::replace(&mut parsed.batch, batch);
+                    // The parser may have finished, in which case the buffers are dropped
+                    parsed.recycle.send(finished).ok();
+                    parsed.next = 0;
+                    parsed.start = 0;
+                }
+                let (key, offset, ends) = &mut parsed.batch.records[parsed.next];
+                let [a, b, c, d] = *ends;
+                let data = &parsed.batch.data;
+                let read = RecordRef {
+                    // Checked by the parser
+                    header: str::from_utf8(&data[parsed.start..a]).expect("header is valid UTF-8"),
+                    seq: &data[a..b],
+                    separator: &data[b..c],
+                    qscore: &data[c..d],
+                };
+                parsed.next += 1;
+                parsed.start = d;
+                self.records += 1;
+                Ok(Some(Keyed { key: mem::take(key), read, offset: *offset }))
+            }
+        }
+    }
+}
+
+impl<R: BufRead + Send + 'static> Records<R> {
+    /// Parse records of `reader` and extract their keys on a thread of their own
+    pub fn spawn(reader: FastqReader<R>, extractor: HeaderKeyExtractor) -> Records<R> {
+        let path = reader.path().to_string();
+        let (sender, receiver) = sync_channel(CHANNEL_BOUND);
+        let (recycle, recycled) = channel();
+        thread::spawn(move || parse(reader, extractor, sender, recycled));
+        let parsed = Parsed { receiver, recycle, batch: Batch::default(), next: 0, start: 0, done: false };
+        Records { source: Source::Threaded(parsed), path, records: 0 }
+    }
+}
+
+/// An output serialized and compressed on a thread of its own. Bytes are
+/// handed over as they're written and only flushed to disk when finished.
+pub struct SinkThread {
+    sender: Option<SyncSender<Vec<u8>>>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl SinkThread {
+    pub fn spawn(mut sink: Sink) -> SinkThread {
+        let (sender, receiver) = sync_channel::<Vec<u8>>(CHANNEL_BOUND);
+        let handle = thread::spawn(move || {
+            for buf in receiver {
+                sink.write_all(&buf)?;
+            }
+            sink.finish()
+        });
+        SinkThread { sender: Some(sender), handle: Some(handle) }
+    }
+
+    /// Wait for the thread, returning the error it stopped with
+    fn join(&mut self) -> io::Result<()> {
+        self.sender = None;
+        match self.handle.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("Output thread panicked")),
+            None => Err(io::Error::other("Output thread already stopped")),
+        }
+    }
+
+    /// Write out everything handed over and close the output
+    pub fn finish(mut self) -> io::Result<()> {
+        self.join()
+    }
+}
+
+impl Write for SinkThread {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(buf.to_vec()).is_ok(),
+            None => false,
+        };
+        if !sent {
+            // The thread hung up, having failed to write
+            self.join()?;
+            return Err(io::Error::other("Output thread stopped early"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,602 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+use std::str;
+use super::Read;
+
+/// The record formats `FastqReader` understands
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Fastq,
+    Fasta,
+}
+
+impl Format {
+    /// Guess the format from the start of a buffered input; FASTA records
+    /// begin with `>`
+    pub fn detect(input: &mut impl BufRead) -> io::Result<Format> {
+        let first = input.fill_buf()?.iter().find(|b| !b.is_ascii_whitespace()).copied();
+        Ok(if first == Some(b'>') { Format::Fasta } else { Format::Fastq })
+    }
+
+    /// Extension for files written in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Fastq => "fastq",
+            Format::Fasta => "fasta",
+        }
+    }
+}
+
+/// What was wrong with a FASTQ or FASTA record
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    Io(io::Error),
+    /// The header line doesn't start with `@`, or `>` for FASTA
+    MissingAt,
+    /// The sequence isn't followed by a line starting with `+`
+    MissingPlus,
+    /// The input ended part way through a record
+    Truncated,
+    /// Sequence and quality lines differ in length
+    LengthMismatch { seq: usize, qual: usize },
+}
+
+/// A malformed or unreadable record, and where it was found
+#[derive(Debug)]
+pub struct ParseError {
+    pub path: String,
+    /// 1-based record number; unknown once the input has been seeked
+    pub record: Option<u64>,
+    /// Byte offset of the start of the record within the (decompressed) FASTQ
+    pub offset: u64,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.record {
+            Some(record) => write!(f, "{}: record {} at byte {}: ", self.path, record, self.offset)?,
+            None => write!(f, "{}: record at byte {}: ", self.path, self.offset)?,
+        }
+        match &self.kind {
+            ParseErrorKind::Io(e) => write!(f, "{}", e),
+            ParseErrorKind::MissingAt => write!(f, "header doesn't start with '@' or '>'"),
+            ParseErrorKind::MissingPlus => write!(f, "separator line doesn't start with '+'"),
+            ParseErrorKind::Truncated => write!(f, "record is truncated"),
+            ParseErrorKind::LengthMismatch { seq, qual } =>
+                write!(f, "sequence has {} bases but quality has {} scores", seq, qual),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// A record borrowed from a `FastqReader`'s buffer, valid until the next
+/// record is read. Line endings are trimmed and wrapped lines joined; only
+/// the header has to be UTF-8.
+#[derive(Clone, Copy, Debug)]
+pub struct RecordRef<'a> {
+    pub header: &'a str,
+    pub seq: &'a [u8],
+    /// The `+` line, including any comment repeated after it. Empty for FASTA.
+    pub separator: &'a [u8],
+    /// Empty for FASTA
+    pub qscore: &'a [u8],
+}
+
+impl<'a> RecordRef<'a> {
+    pub fn format(&self) -> Format {
+        if self.header.starts_with('>') { Format::Fasta } else { Format::Fastq }
+    }
+
+    /// Write the record in the format it was read in, unwrapped and with
+    /// `\n` line endings
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let lines = match self.format() {
+            Format::Fastq => &[self.header.as_bytes(), self.seq, self.separator, self.qscore][..],
+            Format::Fasta => &[self.header.as_bytes(), self.seq][..],
+        };
+        for line in lines {
+            w.write_all(line)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let len = line.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+    &line[..len]
+}
+
+/// Whether a line can be part of a wrapped FASTQ sequence. This tells a
+/// missing `+` line apart from the sequence carrying on.
+fn is_sequence(line: &[u8]) -> bool {
+    !line.is_empty() && line.iter().all(|&b| b.is_ascii_alphabetic() || b == b'.' || b == b'*')
+}
+
+/// Size the read buffer starts at; it grows to fit longer records
+const BUFFER_SIZE: usize = 1 << 16;
+
+/// One or more lines of a record, without the final line ending. Wrapped
+/// sequence and quality keep their inner line endings until joined.
+#[derive(Clone, Copy, Debug, Default)]
+struct Span {
+    start: usize,
+    end: usize,
+    lines: usize,
+}
+
+impl Span {
+    fn get(self, data: &[u8]) -> &[u8] {
+        &data[self.start..self.end]
+    }
+
+    /// Append the lines to `out` without their line endings
+    fn join_into(self, data: &[u8], out: &mut Vec<u8>) {
+        for line in self.get(data).split(|&b| b == b'\n') {
+            out.extend_from_slice(trim_end(line));
+        }
+    }
+}
+
+/// Where a record's lines lie within the data it was parsed from
+#[derive(Clone, Copy, Debug, Default)]
+struct Layout {
+    /// Just past the record's last line ending
+    end: usize,
+    header: Span,
+    seq: Span,
+    separator: Span,
+    qscore: Span,
+}
+
+/// Why `parse` stopped short of a record
+enum Stop {
+    /// Only blank lines were left before the end of the input
+    Eof,
+    /// The record may carry on past the end of the data read so far
+    More,
+    /// The record starting at this position is malformed
+    Invalid(usize, ParseErrorKind),
+}
+
+/// Walks the lines of the data read so far
+struct Lines<'a> {
+    data: &'a [u8],
+    at: usize,
+    eof: bool,
+}
+
+impl<'a> Lines<'a> {
+    /// The next line with its line ending trimmed, or None at the end of
+    /// the input
+    fn next(&mut self) -> Result<Option<Span>, Stop> {
+        let rest = &self.data[self.at..];
+        let (len, next) = match memchr::memchr(b'\n', rest) {
+            Some(i) => (i, self.at + i + 1),
+            None if !self.eof => return Err(Stop::More),
+            None if rest.is_empty() => return Ok(None),
+            None => (rest.len(), self.data.len()),
+        };
+        let line = Span { start: self.at, end: self.at + trim_end(&rest[..len]).len(), lines: 1 };
+        self.at = next;
+        Ok(Some(line))
+    }
+
+    /// The first byte of the next line, or None at the end of the input
+    fn peek(&self) -> Result<Option<u8>, Stop> {
+        match self.data.get(self.at) {
+            None if !self.eof => Err(Stop::More),
+            byte => Ok(byte.copied()),
+        }
+    }
+}
+
+/// Find the lines of the first record in `data`, skipping blank lines
+/// before it. `eof` says whether `data` runs to the end of the input.
+fn parse(data: &[u8], eof: bool) -> Result<Layout, Stop> {
+    let mut lines = Lines { data, at: 0, eof };
+    let header = loop {
+        match lines.next()? {
+            None => return Err(Stop::Eof),
+            Some(line) if line.start == line.end => {}
+            Some(line) => break line,
+        }
+    };
+    let start = header.start;
+    let mut layout = match data[start] {
+        b'@' => parse_fastq(&mut lines, start)?,
+        b'>' => parse_fasta(&mut lines)?,
+        _ => return Err(Stop::Invalid(start, ParseErrorKind::MissingAt)),
+    };
+    layout.header = header;
+    layout.end = lines.at;
+    Ok(layout)
+}
+
+/// Sequence lines run up to the `+` line. As quality lines can start
+/// with `@` or `+` they are read until there are as many scores as bases.
+fn parse_fastq(lines: &mut Lines, start: usize) -> Result<Layout, Stop> {
+    let data = lines.data;
+    let invalid = |kind| Stop::Invalid(start, kind);
+    let mut seq = lines.next()?.ok_or_else(|| invalid(ParseErrorKind::Truncated))?;
+    let mut seq_len = seq.end - seq.start;
+    let separator = loop {
+        let line = lines.next()?.ok_or_else(|| invalid(ParseErrorKind::Truncated))?;
+        match line.get(data) {
+            [b'+', ..] => break line,
+            bases if is_sequence(bases) => {
+                seq_len += bases.len();
+                seq.end = line.end;
+                seq.lines += 1;
+            }
+            _ => return Err(invalid(ParseErrorKind::MissingPlus)),
+        }
+    };
+    let mut qscore = lines.next()?.ok_or_else(|| invalid(ParseErrorKind::Truncated))?;
+    let mut qual_len = qscore.end - qscore.start;
+    while qual_len < seq_len {
+        let line = match lines.next()? {
+            Some(line) => line,
+            None => break,
+        };
+        // A short quality followed by the next record's header. Quality is
+        // only wrapped if the sequence was, so an unwrapped record ends at
+        // a line that could be a header.
+        let len = line.end - line.start;
+        if qual_len + len > seq_len || (seq.lines == 1 && line.get(data).starts_with(b"@")) {
+            break;
+        }
+        qual_len += len;
+        qscore.end = line.end;
+        qscore.lines += 1;
+    }
+    if qual_len != seq_len {
+        return Err(invalid(ParseErrorKind::LengthMismatch { seq: seq_len, qual: qual_len }));
+    }
+    Ok(Layout { seq, separator, qscore, ..Layout::default() })
+}
+
+/// Sequence lines run up to the next `>` header or the end of the input
+fn parse_fasta(lines: &mut Lines) -> Result<Layout, Stop> {
+    let mut seq = Span { start: lines.at, end: lines.at, lines: 0 };
+    while !matches!(lines.peek()?, None | Some(b'>')) {
+        if let Some(line) = lines.next()? {
+            if seq.lines == 0 {
+                seq.start = line.start;
+            }
+            seq.end = line.end;
+            seq.lines += 1;
+        }
+    }
+    let empty = Span { start: seq.end, end: seq.end, lines: 0 };
+    Ok(Layout { seq, separator: empty, qscore: empty, ..Layout::default() })
+}
+
+/// Wraps a buffered input to keep track of the current record and byte
+/// offset, so that parse errors can say where they happened. Records are
+/// parsed in place in the reader's own buffer and borrowed from it, only
+/// wrapped lines being copied to join them.
+///
+/// Besides four line FASTQ, records may have their sequence and quality
+/// wrapped over several lines, or be FASTA with `>` headers.
+pub struct FastqReader<R> {
+    inner: R,
+    path: String,
+    record: Option<u64>,
+    /// Byte offset within the input of `buffer[pos]`
+    offset: u64,
+    buffer: Vec<u8>,
+    /// Start of the bytes not yet parsed, and end of those read
+    pos: usize,
+    filled: usize,
+    eof: bool,
+    /// Where the current record starts within `buffer`, and its lines
+    current: usize,
+    layout: Layout,
+    /// The current record's sequence and quality if wrapped, joined
+    joined: Vec<u8>,
+    seq_joined: usize,
+}
+
+impl<R: BufRead> FastqReader<R> {
+    /// `path` is only used in error messages
+    pub fn new(inner: R, path: &str) -> FastqReader<R> {
+        FastqReader {
+            inner,
+            path: path.to_string(),
+            record: Some(0),
+            offset: 0,
+            buffer: vec![0; BUFFER_SIZE],
+            pos: 0,
+            filled: 0,
+            eof: false,
+            current: 0,
+            layout: Layout::default(),
+            joined: Vec::new(),
+            seq_joined: 0,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Byte offset within the (decompressed) input of the next record
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn error(&self, start: u64, kind: ParseErrorKind) -> ParseError {
+        ParseError { path: self.path.clone(), record: self.record, offset: start, kind }
+    }
+
+    /// Read more of the input after what is buffered, first moving the
+    /// bytes not yet parsed to the front of the buffer, or growing it if
+    /// they fill it
+    fn refill(&mut self) -> io::Result<()> {
+        if self.pos > 0 {
+            self.buffer.copy_within(self.pos..self.filled, 0);
+            self.filled -= self.pos;
+            self.pos = 0;
+        }
+        if self.filled == self.buffer.len() {
+            self.buffer.resize(self.buffer.len() * 2, 0);
+        }
+        loop {
+            match self.inner.read(&mut self.buffer[self.filled..]) {
+                Ok(0) => self.eof = true,
+                Ok(len) => self.filled += len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            return Ok(());
+        }
+    }
+
+    /// Parse the next record in the buffer, checking it is well formed.
+    /// Returns false at EOF; blank lines between records are skipped.
+    fn fill_record(&mut self) -> Result<bool, ParseError> {
+        let layout = loop {
+            match parse(&self.buffer[self.pos..self.filled], self.eof) {
+                Ok(layout) => break layout,
+                Err(Stop::More) => self.refill().map_err(|e| self.error(self.offset, ParseErrorKind::Io(e)))?,
+                Err(Stop::Eof) => {
+                    self.consume(self.filled - self.pos);
+                    return Ok(false);
+                }
+                Err(Stop::Invalid(start, kind)) => {
+                    self.record = self.record.map(|record| record + 1);
+                    return Err(self.error(self.offset + start as u64, kind));
+                }
+            }
+        };
+        self.record = self.record.map(|record| record + 1);
+        let start = self.offset + layout.header.start as u64;
+        self.current = self.pos;
+        self.layout = layout;
+        self.consume(layout.end);
+        let data = &self.buffer[self.current..];
+        if str::from_utf8(layout.header.get(data)).is_err() {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "header is not valid UTF-8");
+            return Err(self.error(start, ParseErrorKind::Io(e)));
+        }
+        if layout.seq.lines > 1 || layout.qscore.lines > 1 {
+            self.joined.clear();
+            layout.seq.join_into(data, &mut self.joined);
+            self.seq_joined = self.joined.len();
+            layout.qscore.join_into(data, &mut self.joined);
+        }
+        Ok(true)
+    }
+
+    /// The headers of up to `count` records from the next, without
+    /// consuming them. Stops early at the end of the input or a record
+    /// that doesn't parse, leaving the error for when it is read.
+    pub fn sample_headers(&mut self, count: usize) -> io::Result<Vec<String>> {
+        let mut headers = Vec::new();
+        // Relative to `pos`, which refilling moves
+        let mut at = 0;
+        while headers.len() < count {
+            match parse(&self.buffer[self.pos + at..self.filled], self.eof) {
+                Ok(layout) => {
+                    match str::from_utf8(layout.header.get(&self.buffer[self.pos + at..])) {
+                        Ok(header) => headers.push(header.to_string()),
+                        Err(_) => break,
+                    }
+                    at += layout.end;
+                }
+                Err(Stop::More) => self.refill()?,
+                Err(_) => break,
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Parse the next record without copying it out of the reader.
+    /// Returns None at EOF; blank lines between records are skipped.
+    pub fn next_record(&mut self) -> Result<Option<RecordRef<'_>>, ParseError> {
+        Ok(if self.fill_record()? { Some(self.current()) } else { None })
+    }
+
+    fn current(&self) -> RecordRef<'_> {
+        let data = &self.buffer[self.current..];
+        let layout = &self.layout;
+        let (seq, qscore) = if layout.seq.lines > 1 || layout.qscore.lines > 1 {
+            self.joined.split_at(self.seq_joined)
+        } else {
+            (layout.seq.get(data), layout.qscore.get(data))
+        };
+        RecordRef {
+            // Checked by `fill_record`
+            header: str::from_utf8(layout.header.get(data)).expect("header is valid UTF-8"),
+            seq,
+            separator: layout.separator.get(data),
+            qscore,
+        }
+    }
+}
+
+impl<R: BufRead> io::Read for FastqReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for FastqReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled && !self.eof {
+            self.refill()?;
+        }
+        Ok(&self.buffer[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+        self.offset += amt as u64;
+    }
+}
+
+impl<R: BufRead + Seek> Seek for FastqReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Current(delta) => SeekFrom::Start((self.offset as i64 + delta) as u64),
+            pos => pos,
+        };
+        self.offset = self.inner.seek(pos)?;
+        self.pos = 0;
+        self.filled = 0;
+        self.eof = false;
+        self.record = None;
+        Ok(self.offset)
+    }
+
+    // Avoid seeking, which would throw away the read buffer
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.offset)
+    }
+}
+
+impl<R: BufRead + Seek> FastqReader<R> {
+    /// Parse the record starting at byte `pos`, e.g. one found by indexing
+    pub fn read_at(&mut self, pos: u64) -> Result<RecordRef<'_>, ParseError> {
+        self.seek(SeekFrom::Start(pos)).map_err(|e| self.error(pos, ParseErrorKind::Io(e)))?;
+        if !self.fill_record()? {
+            return Err(self.error(pos, ParseErrorKind::Truncated));
+        }
+        Ok(self.current())
+    }
+}
+
+/// Parses the next record into an owned `Read`, each line ending in `\n`.
+/// Wrapped lines are joined, and FASTA records have no `+` or quality line.
+/// Returns None at EOF; blank lines between records are skipped.
+/// `FastqReader::next_record` avoids the copy.
+pub fn parse_read<R: BufRead>(file: &mut FastqReader<R>) -> Result<Option<Read>, ParseError> {
+    let start = file.offset;
+    if !file.fill_record()? {
+        return Ok(None);
+    }
+    let line = |line: &[u8]| String::from_utf8([line, b"\n"].concat())
+        .map_err(|e| file.error(start, ParseErrorKind::Io(io::Error::new(io::ErrorKind::InvalidData, e))));
+    let record = file.current();
+    let fastq = record.format() == Format::Fastq;
+    Ok(Some(Read {
+        header: line(record.header.as_bytes())?,
+        seq: line(record.seq)?,
+        separator: if fastq { line(record.separator)? } else { String::new() },
+        qscore: if fastq { line(record.qscore)? } else { String::new() },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(data: &str) -> Result<usize, ParseError> {
+        let mut reader = FastqReader::new(data.as_bytes(), "test.fastq");
+        let mut count = 0;
+        while parse_read(&mut reader)?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn test_parse_valid() {
+        assert_eq!(parse_all("@a\nACGT\n+\nIIII\n@b\nAC\n+b\nII").unwrap(), 2);
+        assert_eq!(parse_all("@a\nACGT\n+\nIIII\n\n").unwrap(), 1);
+        assert_eq!(parse_all("").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_next_record() {
+        let mut reader = FastqReader::new("@a 1\r\nACGT\r\n+a\r\nIIII\r\n\n@b\nAC\n+\nII".as_bytes(), "test.fastq");
+        let read = reader.next_record().unwrap().unwrap();
+        assert_eq!((read.header, read.seq, read.separator, read.qscore), ("@a 1", &b"ACGT"[..], &b"+a"[..], &b"IIII"[..]));
+        let read = reader.next_record().unwrap().unwrap();
+        let mut written = Vec::new();
+        read.write_to(&mut written).unwrap();
+        assert_eq!(written, b"@b\nAC\n+\nII\n");
+        assert!(reader.next_record().unwrap().is_none());
+        let mut reader = FastqReader::new(&b"@a\xff\nAC\n+\nII\n"[..], "test.fastq");
+        assert!(matches!(reader.next_record().unwrap_err().kind, ParseErrorKind::Io(_)));
+    }
+
+    #[test]
+    fn test_wrapped_and_fasta() {
+        // Wrapped quality lines may start with '@' or '+'
+        let mut reader = FastqReader::new("@a\nACGT\nAC\n+\nIIII\n@+\n@b\nA\n+\n@\n".as_bytes(), "test.fastq");
+        let read = reader.next_record().unwrap().unwrap();
+        assert_eq!((read.seq, read.qscore), (&b"ACGTAC"[..], &b"IIII@+"[..]));
+        assert_eq!(reader.next_record().unwrap().unwrap().qscore, b"@");
+        assert!(reader.next_record().unwrap().is_none());
+        let mut reader = FastqReader::new(">a desc\nACGT\n\nAC\n>b\n>c\nTT".as_bytes(), "test.fasta");
+        let read = reader.next_record().unwrap().unwrap();
+        assert_eq!((read.header, read.seq, read.format()), (">a desc", &b"ACGTAC"[..], Format::Fasta));
+        let mut written = Vec::new();
+        read.write_to(&mut written).unwrap();
+        assert_eq!(written, b">a desc\nACGTAC\n");
+        assert_eq!(reader.next_record().unwrap().unwrap().seq, b"");
+        assert_eq!(reader.next_record().unwrap().unwrap().seq, b"TT");
+        assert!(reader.next_record().unwrap().is_none());
+        assert_eq!(Format::detect(&mut "\n>a\nAC\n".as_bytes()).unwrap(), Format::Fasta);
+        assert_eq!(Format::detect(&mut "@a\nAC\n".as_bytes()).unwrap(), Format::Fastq);
+    }
+
+    #[test]
+    fn test_records_across_reads() {
+        // One byte per read, so every record is parsed over several refills
+        let data = "\n@a\nACGT\nAC\n+\nIIII\nII\n@b\nAC\n+\nII\n".repeat(3) + ">c\nAC\nGT";
+        let mut reader = FastqReader::new(io::BufReader::with_capacity(1, data.as_bytes()), "test.fastq");
+        assert_eq!(reader.sample_headers(3).unwrap(), ["@a", "@b", "@a"]);
+        for _ in 0..3 {
+            assert_eq!(reader.next_record().unwrap().unwrap().qscore, b"IIIIII");
+            assert_eq!(reader.next_record().unwrap().unwrap().seq, b"AC");
+        }
+        assert_eq!(reader.next_record().unwrap().unwrap().seq, b"ACGT");
+        assert!(reader.next_record().unwrap().is_none());
+        assert_eq!(reader.offset(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse_all("@a\nACGT\n+\nIIII\n@b\nACGT\n+\nIII\n").unwrap_err();
+        assert_eq!((err.record, err.offset), (Some(2), 15));
+        assert!(matches!(err.kind, ParseErrorKind::LengthMismatch { seq: 4, qual: 3 }));
+        assert_eq!(err.to_string(), "test.fastq: record 2 at byte 15: sequence has 4 bases but quality has 3 scores");
+        // A short quality isn't made up by the next record's header
+        let err = parse_all("@a/1\nACGTACGT\n+\nIIII\n@b/1\nACGT\n+\nIIII\n").unwrap_err();
+        assert_eq!((err.record, err.offset), (Some(1), 0));
+        assert!(matches!(err.kind, ParseErrorKind::LengthMismatch { seq: 8, qual: 4 }));
+        assert!(matches!(parse_all("a\nACGT\n+\nIIII\n").unwrap_err().kind, ParseErrorKind::MissingAt));
+        assert!(matches!(parse_all("@a\nACGT\n-\nIIII\n").unwrap_err().kind, ParseErrorKind::MissingPlus));
+        assert!(matches!(parse_all("@a\nACGT\n+\nIIII\n@b\nACGT\n").unwrap_err().kind, ParseErrorKind::Truncated));
+    }
+}
@@ -0,0 +1,313 @@
+use std::convert::TryFrom;
+use std::io::{self, BufRead};
+use super::Mate;
+
+/// Magic bytes at the start of a decompressed BAM file
+pub const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+
+// SAM flag bits
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_READ2: u16 = 0x80;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// 4-bit base encoding used by BAM
+const BAM_BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// Largest BAM record accepted, well beyond any real read
+const MAX_RECORD_SIZE: usize = 64 << 20;
+
+/// Alignment file flavours that can be converted to FASTQ
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Sam,
+    Bam,
+}
+
+/// The parts of an alignment record needed to rebuild a FASTQ read
+struct Record {
+    name: Vec<u8>,
+    flag: u16,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+/// Streams the reads of a single mate from an unaligned SAM/BAM as FASTQ
+/// text, so they can be parsed like any other FASTQ input. Records without
+/// the READ2 flag are treated as read 1; secondary and supplementary records
+/// are skipped as they repeat a primary record's sequence.
+pub struct FastqStream<R: BufRead> {
+    inner: R,
+    format: Format,
+    mate: Mate,
+    started: bool,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> FastqStream<R> {
+    pub fn new(inner: R, format: Format, mate: Mate) -> FastqStream<R> {
+        FastqStream {
+            inner,
+            format,
+            mate,
+            started: false,
+            buffer: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Refill the buffer with the next record of our mate. Returns false at EOF.
+    fn fill_buffer(&mut self) -> io::Result<bool> {
+        if !self.started {
+            if self.format == Format::Bam {
+                skip_bam_header(&mut self.inner)?;
+            }
+            self.started = true;
+        }
+        loop {
+            let record = match self.format {
+                Format::Sam => read_sam_record(&mut self.inner)?,
+                Format::Bam => read_bam_record(&mut self.inner)?,
+            };
+            let record = match record {
+                Some(record) => record,
+                None => return Ok(false),
+            };
+            if record.flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0 {
+                continue;
+            }
+            let mate = if record.flag & FLAG_READ2 != 0 { Mate::R2 } else { Mate::R1 };
+            if mate != self.mate {
+                continue;
+            }
+            self.buffer.clear();
+            self.pos = 0;
+            write_fastq(&mut self.buffer, record, mate);
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: BufRead> io::Read for FastqStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() && !self.fill_buffer()? {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Format a record as FASTQ, restoring the sequenced orientation of reverse-strand records
+fn write_fastq(out: &mut Vec<u8>, mut record: Record, mate: Mate) {
+    if record.flag & FLAG_REVERSE != 0 {
+        record.seq = reverse_complement(&record.seq);
+        record.qual.reverse();
+    }
+    out.push(b'@');
+    out.extend_from_slice(&record.name);
+    out.extend_from_slice(format!("/{}\n", mate.number()).as_bytes());
+    out.extend_from_slice(&record.seq);
+    out.extend_from_slice(b"\n+\n");
+    out.extend_from_slice(&record.qual);
+    out.push(b'\n');
+}
+
+/// Reverse complement a sequence, including IUPAC ambiguity codes
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|base| match base {
+        b'A' => b'T', b'T' => b'A', b'C' => b'G', b'G' => b'C',
+        b'a' => b't', b't' => b'a', b'c' => b'g', b'g' => b'c',
+        b'R' => b'Y', b'Y' => b'R', b'K' => b'M', b'M' => b'K',
+        b'B' => b'V', b'V' => b'B', b'D' => b'H', b'H' => b'D',
+        other => *other,
+    }).collect()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Read the next alignment line, skipping SAM header lines
+fn read_sam_record(input: &mut impl BufRead) -> io::Result<Option<Record>> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.starts_with('@') && !line.trim().is_empty() {
+            break;
+        }
+    }
+    let fields: Vec<&str> = line.trim_end_matches(['\n', '\r']).split('\t').collect();
+    if fields.len() < 11 {
+        return Err(invalid_data("SAM record has fewer than 11 fields"));
+    }
+    let flag = fields[1].parse().map_err(|_| invalid_data("Invalid SAM flag"))?;
+    let seq = if fields[9] == "*" { Vec::new() } else { fields[9].as_bytes().to_vec() };
+    // Missing qualities are written as Q0
+    let qual = if fields[10] == "*" { vec![b'!'; seq.len()] } else { fields[10].as_bytes().to_vec() };
+    Ok(Some(Record { name: fields[0].as_bytes().to_vec(), flag, seq, qual }))
+}
+
+fn read_i32(input: &mut impl BufRead) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+/// A length field, rejected if negative or over `limit`
+fn length(value: i32, what: &str, limit: usize) -> io::Result<usize> {
+    match usize::try_from(value) {
+        Ok(len) if len <= limit => Ok(len),
+        _ => Err(invalid_data(&format!("Invalid BAM {} {}", what, value))),
+    }
+}
+
+fn skip(input: &mut impl BufRead, len: usize) -> io::Result<()> {
+    let skipped = io::copy(&mut io::Read::take(&mut *input, len as u64), &mut io::sink())?;
+    if skipped != len as u64 {
+        return Err(invalid_data("Truncated BAM header"));
+    }
+    Ok(())
+}
+
+/// Skip the magic, header text and reference dictionary of a BAM
+fn skip_bam_header(input: &mut impl BufRead) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != BAM_MAGIC {
+        return Err(invalid_data("Not a BAM file"));
+    }
+    let l_text = length(read_i32(input)?, "header length", usize::MAX)?;
+    skip(input, l_text)?;
+    let n_ref = read_i32(input)?;
+    for _ in 0..n_ref {
+        let l_name = length(read_i32(input)?, "reference name length", usize::MAX - 4)?;
+        skip(input, l_name + 4)?;
+    }
+    Ok(())
+}
+
+/// Read the next BAM alignment record, decoding its name, sequence and qualities
+fn read_bam_record(input: &mut impl BufRead) -> io::Result<Option<Record>> {
+    if input.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let block_size = length(read_i32(input)?, "record size", MAX_RECORD_SIZE)?;
+    if block_size < 32 {
+        return Err(invalid_data("BAM record too short"));
+    }
+    let mut block = vec![0u8; block_size];
+    input.read_exact(&mut block)?;
+    let l_read_name = block[8] as usize;
+    let n_cigar_op = u16::from_le_bytes([block[12], block[13]]) as usize;
+    let flag = u16::from_le_bytes([block[14], block[15]]);
+    let l_seq = length(i32::from_le_bytes([block[16], block[17], block[18], block[19]]), "sequence length", block_size)?;
+    let name_start = 32;
+    let seq_start = name_start + l_read_name + 4 * n_cigar_op;
+    let qual_start = seq_start.checked_add(l_seq.div_ceil(2));
+    let qual_start = match qual_start {
+        Some(start) if start.checked_add(l_seq).is_some_and(|end| end <= block.len()) && l_read_name > 0 => start,
+        _ => return Err(invalid_data("BAM record is truncated")),
+    };
+    // Read name is NUL terminated
+    let name = block[name_start..name_start + l_read_name - 1].to_vec();
+    let seq = (0..l_seq).map(|i| {
+        let byte = block[seq_start + i / 2];
+        let code = if i % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+        BAM_BASES[code as usize]
+    }).collect();
+    let qual = block[qual_start..qual_start + l_seq].iter()
+        .map(|&q| if q == 0xff { b'!' } else { q.saturating_add(33) })
+        .collect();
+    Ok(Some(Record { name, flag, seq, qual }))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::{BufReader, Read};
+    use super::*;
+
+    /// Encode a minimal unaligned BAM (decompressed) holding the given records
+    pub fn encode_bam(records: &[(&str, u16, &str, &str)]) -> Vec<u8> {
+        let mut bam = BAM_MAGIC.to_vec();
+        let text = b"@HD\tVN:1.6\tSO:unsorted\n";
+        bam.extend_from_slice(&(text.len() as i32).to_le_bytes());
+        bam.extend_from_slice(text);
+        bam.extend_from_slice(&0i32.to_le_bytes());
+        for (name, flag, seq, qual) in records {
+            let mut block = Vec::new();
+            block.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+            block.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+            block.push(name.len() as u8 + 1);
+            block.push(0); // mapq
+            block.extend_from_slice(&4680u16.to_le_bytes()); // bin
+            block.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+            block.extend_from_slice(&flag.to_le_bytes());
+            block.extend_from_slice(&(seq.len() as i32).to_le_bytes());
+            block.extend_from_slice(&(-1i32).to_le_bytes()); // next_refID
+            block.extend_from_slice(&(-1i32).to_le_bytes()); // next_pos
+            block.extend_from_slice(&0i32.to_le_bytes()); // tlen
+            block.extend_from_slice(name.as_bytes());
+            block.push(0);
+            let codes: Vec<u8> = seq.bytes()
+                .map(|b| BAM_BASES.iter().position(|&c| c == b).unwrap() as u8)
+                .collect();
+            for pair in codes.chunks(2) {
+                block.push(pair[0] << 4 | pair.get(1).cloned().unwrap_or(0));
+            }
+            block.extend(qual.bytes().map(|q| q - 33));
+            bam.extend_from_slice(&(block.len() as i32).to_le_bytes());
+            bam.extend_from_slice(&block);
+        }
+        bam
+    }
+
+    fn convert(data: &[u8], format: Format, mate: Mate) -> String {
+        let mut fastq = String::new();
+        FastqStream::new(BufReader::new(data), format, mate).read_to_string(&mut fastq).unwrap();
+        fastq
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"AACGTN"), b"NACGTT".to_vec());
+    }
+
+    #[test]
+    fn test_sam_to_fastq() {
+        let sam = "@HD\tVN:1.6\n\
+                   r1\t77\t*\t0\t0\t*\t*\t0\t0\tACGTA\tABCDE\n\
+                   r1\t157\t*\t0\t0\t*\t*\t0\t0\tAACCG\tFGHIJ\n\
+                   r1\t333\t*\t0\t0\t*\t*\t0\t0\tAAAAA\tFFFFF\n";
+        assert_eq!(convert(sam.as_bytes(), Format::Sam, Mate::R1), "@r1/1\nACGTA\n+\nABCDE\n");
+        // Read 2 is flagged reverse (0x10), so it's reverse complemented
+        assert_eq!(convert(sam.as_bytes(), Format::Sam, Mate::R2), "@r1/2\nCGGTT\n+\nJIHGF\n");
+    }
+
+    #[test]
+    fn test_bam_to_fastq() {
+        let bam = encode_bam(&[("r1", 77, "ACGTA", "ABCDE"), ("r1", 141, "AACCG", "FGHIJ")]);
+        assert_eq!(convert(&bam, Format::Bam, Mate::R1), "@r1/1\nACGTA\n+\nABCDE\n");
+        assert_eq!(convert(&bam, Format::Bam, Mate::R2), "@r1/2\nAACCG\n+\nFGHIJ\n");
+    }
+
+    #[test]
+    fn test_corrupt_bam_lengths() {
+        let record = encode_bam(&[]).len();
+        let bam = encode_bam(&[("r1", 77, "ACGTA", "ABCDE")]);
+        // Record size, then sequence length, set negative or far too large
+        for (field, value) in &[(0, -1), (0, i32::MAX), (20, -1), (20, i32::MAX)] {
+            let mut corrupt = bam.clone();
+            corrupt[record + field..record + field + 4].copy_from_slice(&value.to_le_bytes());
+            let mut fastq = String::new();
+            let err = FastqStream::new(BufReader::new(&corrupt[..]), Format::Bam, Mate::R1).read_to_string(&mut fastq).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        }
+    }
+}
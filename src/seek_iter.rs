@@ -0,0 +1,84 @@
+use fastq_pair::{create_seekable_io, Config, FastqReader, KeySet, Keyed, Mate, Output};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use super::Result;
+
+/// Pair two FASTQ files by iterating over both files simultaneously, like
+/// "iter_both", but only storing the byte offset of unmatched reads. Once a
+/// mate is found the stored read is recovered by seeking into a second
+/// handle on its file, so memory only grows with the number of headers
+/// waiting for a mate.
+pub fn pair_fastqs(r1_path: &str, r2_path: &str, config: &Config) -> Result<Output> {
+    let mut io = create_seekable_io(r1_path, r2_path, config)?;
+    // Separate handles for random access so the streaming readers keep their buffers
+    let mut lookup1 = FastqReader::new(BufReader::new(File::open(&io.r1_in_path)?), io.in_read1.path());
+    let mut lookup2 = FastqReader::new(BufReader::new(File::open(&io.r2_in_path)?), io.in_read2.path());
+    let mut index1: HashMap<String, u64> = HashMap::new();
+    let mut index2: HashMap<String, u64> = HashMap::new();
+    // Keys already paired, if asked to spot reads repeating them
+    let mut paired = KeySet::default();
+    let remember = io.out.remember_paired;
+    let (mut read1_finished, mut read2_finished) = (false, false);
+    while !(read1_finished && read2_finished) {
+        // A read whose mate is waiting can't be a duplicate, as the mate
+        // would have been paired with the earlier read
+        if let Some(Keyed { key: header1, read: read1, offset: pos1 }) = io.in_read1.next_record()? {
+            if let Some(pos2) = index2.remove(&header1) {
+                let read2 = lookup2.read_at(pos2)?;
+                io.out.write_pair(&header1, &read1, &read2)?;
+                if remember {
+                    paired.insert(&header1);
+                }
+            } else if !(index1.contains_key(&header1) || paired.contains(&header1))
+                || io.out.duplicate(&header1, &read1, Mate::R1, index1.contains_key(&header1))? {
+                index1.insert(header1, pos1);
+            }
+        } else { read1_finished = true }
+        if let Some(Keyed { key: header2, read: read2, offset: pos2 }) = io.in_read2.next_record()? {
+            if let Some(pos1) = index1.remove(&header2) {
+                let read1 = lookup1.read_at(pos1)?;
+                io.out.write_pair(&header2, &read1, &read2)?;
+                if remember {
+                    paired.insert(&header2);
+                }
+            } else if !(index2.contains_key(&header2) || paired.contains(&header2))
+                || io.out.duplicate(&header2, &read2, Mate::R2, index2.contains_key(&header2))? {
+                index2.insert(header2, pos2);
+            }
+        } else { read2_finished = true }
+    }
+    // Write out singletons, in file order so the lookups only move forward
+    for (index, lookup, mate) in [(index1, &mut lookup1, Mate::R1), (index2, &mut lookup2, Mate::R2)].iter_mut() {
+        let mut positions: Vec<(String, u64)> = index.drain().collect();
+        positions.sort_unstable_by_key(|&(_, pos)| pos);
+        for (key, pos) in positions {
+            let read = lookup.read_at(pos)?;
+            io.out.write_single(&key, &read, *mate)?;
+        }
+    }
+    io.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{records, shuffled_inputs};
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pair_fastqs() {
+        let tmpdir = tempdir().unwrap();
+        let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+        let output = pair_fastqs(&r1_path, &r2_path, &Config::default()).expect("Pairing failed");
+
+        // Mates are emitted as soon as they are seen, which for this data matches the R2 order
+        assert_eq!(records(&output.r1_out_path), records("data/ncbi_1_paired.fastq"));
+        assert_eq!(records(&output.r2_out_path.unwrap()), records("data/ncbi_2_paired.fastq"));
+        let mut singletons = records(&output.singleton_path.unwrap());
+        singletons.sort();
+        let mut expected = records("data/ncbi_unpaired.fastq");
+        expected.sort();
+        assert_eq!(singletons, expected);
+    }
+}
@@ -1,52 +1,68 @@
-use fastq_pair::{create_io, delete_empty_fastq, Output, parse_header, parse_read, PartialRead};
+use fastq_pair::{create_io, Config, KeySet, Keyed, Mate, Output, PartialRead, Records, Writers};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::BufRead;
+use std::mem;
 use super::Result;
 
 
 /// Pair FASTQ files and write out R1/R2 and singleton file
-pub fn pair_fastqs(r1_path: &str, r2_path: &str) -> Result<Output> {
-    let mut io = create_io(&r1_path, &r2_path)?;
-    let mut map = index_read(&mut io.in_read1)?;
-    while let Some(read) = parse_read(&mut io.in_read2) {
-        // Check if header is in hashmap
-        let header = parse_header(&read.header)?;
-        if map.contains_key(&header) {
-            // Write to BufWriters and remove from HashMap
-            let r1 = &map[&header];
-            write!(&mut io.out_read1, "{}.1\n{}+\n{}", &header, r1.seq, r1.qscore)?;
-            write!(&mut io.out_read2, "{}.2\n{}+\n{}", &header, read.seq, read.qscore)?;
-            map.remove(&header);
-        } else {
-            // Else: Write out R2 to singleton file
-            write!(&mut io.out_single, "{}.2\n{}+\n{}", &header, read.seq, read.qscore)?;
+pub fn pair_fastqs(r1_path: &str, r2_path: &str, config: &Config) -> Result<Output> {
+    let mut io = create_io(r1_path, r2_path, config)?;
+    let map = index_read(&mut io.in_read1, &mut io.out)?;
+    write_pairs(map, &mut io.in_read2, &mut io.out)?;
+    io.finish()
+}
+
+
+/// Stream R2 reads against an index of R1 reads, writing pairs as they are
+/// found. Whatever is left in the index afterwards is written out as singletons.
+pub fn write_pairs(mut map: HashMap<String, PartialRead>,
+                   in_read2: &mut Records<impl BufRead>,
+                   out: &mut Writers) -> Result<()> {
+    // R2 reads have all been written by the time a duplicate turns up.
+    // Paired R1 reads are left in the map emptied, so that repeats of their
+    // key are spotted there, leaving only R2 singletons to remember.
+    let mut singles2 = KeySet::default();
+    while let Some(Keyed { key: header, read, .. }) = in_read2.next_record()? {
+        match map.get_mut(&header) {
+            Some(r1) if !r1.is_empty() => {
+                let r1 = mem::take(r1);
+                out.write_pair(&header, &r1.as_record(), &read)?;
+            }
+            Some(_) => {
+                out.duplicate(&header, &read, Mate::R2, false)?;
+            }
+            None if !singles2.insert(&header) => {
+                out.duplicate(&header, &read, Mate::R2, false)?;
+            }
+            // Write out R2 to singleton file
+            None => out.write_single(&header, &read, Mate::R2)?,
         }
     }
     // Write out remainder of singletons left in R1
-    for key in map.keys() {
-        let r1 = &map[key];
-        write!(&mut io.out_single, "{}.1\n{}+\n{}", &key, r1.seq, r1.qscore)?;
-        // TODO: Add map.remove() here?
+    for (key, r1) in map.drain().filter(|(_, r1)| !r1.is_empty()) {
+        out.write_single(&key, &r1.as_record(), Mate::R1)?;
     }
-    // Flush output for empty fastq check
-    &mut io.out_single.flush()?;
-
-    Ok(Output {
-        r1_out_path: io.r1_out_path,
-        r2_out_path: io.r2_out_path,
-        singleton_path: delete_empty_fastq(&io.singleton_path),
-    })
+    Ok(())
 }
 
 
 /// Create a HashMap associating the unique component of a header to it's
-/// sequence and quality score.
-pub fn index_read(in_read: &mut BufReader<File>) -> Result<HashMap<String, PartialRead>> {
+/// sequence and quality score. Repeated headers are dealt with by `out`.
+pub fn index_read(in_read: &mut Records<impl BufRead>, out: &mut Writers) -> Result<HashMap<String, PartialRead>> {
     let mut map = HashMap::new();
-    while let Some(read) = parse_read(in_read) {
-        let header = parse_header(&read.header)?;
-        map.insert(header, PartialRead { seq: read.seq, qscore: read.qscore });
+    while let Some(Keyed { key: header, read, .. }) = in_read.next_record()? {
+        match map.entry(header) {
+            Entry::Occupied(mut entry) => {
+                if out.duplicate(entry.key(), &read, Mate::R1, true)? {
+                    entry.insert(read.into());
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(read.into());
+            }
+        }
     }
     Ok(map)
 }
@@ -54,23 +70,28 @@ pub fn index_read(in_read: &mut BufReader<File>) -> Result<HashMap<String, Parti
 
 #[cfg(test)]
 mod tests {
+    use crate::testing::scratch_io;
     use std::fs::copy;
+    use fastq_pair::{open_fastq, parse_header, parse_read};
     use std::path::Path;
+    use fastq_pair::{HeaderKeyExtractor, HeaderStyle};
     use super::*;
     use tempfile::tempdir;
 
     #[test]
     fn test_store_read1() {
-        let file = File::open("data/ncbi_1_paired.fastq").unwrap();
-        let mut handle = BufReader::new(file);
-        let map = index_read(&mut handle).unwrap();
-        let read = &map["@SRR3380692.1"];
+        let tmpdir = tempdir().unwrap();
+        let mut io = scratch_io(tmpdir.path());
+        let handle = open_fastq("data/ncbi_1_paired.fastq").unwrap();
+        let map = index_read(&mut Records::new(handle, HeaderKeyExtractor::Style(HeaderStyle::Sra)), &mut io.out).unwrap();
+        let read = map["@SRR3380692.1"].as_record();
         let seq = "ATTGTNTTATTCTATAAAACATTTCAAACCTAGTTAGAGATTTGTAATCAAA\
-                    AAACATTTGCGCAGAAAGCAGCACTTAGGGCTGCCTGTTCTATACCCTA\n";
+                    AAACATTTGCGCAGAAAGCAGCACTTAGGGCTGCCTGTTCTATACCCTA";
         let qscore = "@@@DD#4AFHHHHJJJJIJJJJJJJJJJJJIIJHGJJIJJJIJJGHGIIJ\
-                    JJJIJJJJJJJJIJJHHHFFFFFEEEEEDDDDDDDDDDDCCDEEEFDCDDC\n";
-        assert_eq!(seq.to_string(), read.seq);
-        assert_eq!(qscore.to_string(), read.qscore);
+                    JJJIJJJJJJJJIJJHHHFFFFFEEEEEDDDDDDDDDDDCCDEEEFDCDDC";
+        assert_eq!(seq.as_bytes(), read.seq);
+        assert_eq!(qscore.as_bytes(), read.qscore);
+        assert_eq!(read.header, "@SRR3380692.1.1 1 length=101");
     }
 
     #[test]
@@ -81,22 +102,20 @@ mod tests {
         copy("data/ncbi_1_shuffled.fastq", &r1_path).unwrap();
         let input2 = tmppath.join("ncbi_2_shuffled.fastq");
         copy("data/ncbi_2_shuffled.fastq", &input2).unwrap();
-        pair_fastqs(r1_path.to_str().unwrap(), input2.to_str().unwrap()).unwrap();
+        pair_fastqs(r1_path.to_str().unwrap(), input2.to_str().unwrap(), &Config::default()).unwrap();
         // Output exists
         let outputs = [tmppath.join("R1_paired.fastq"),
             tmppath.join("R2_paired.fastq"),
             tmppath.join("Singletons.fastq")];
         for output in &outputs {
-            assert_eq!(Path::exists(Path::new(output)), true);
+            assert!(Path::exists(Path::new(output)));
         }
         // Assert each header in each file matches
-        let h1 = File::open(&outputs[0]).unwrap();
-        let h2 = File::open(&outputs[1]).unwrap();
-        let mut reader1 = BufReader::new(&h1);
-        let mut reader2 = BufReader::new(&h2);
+        let mut reader1 = open_fastq(outputs[0].to_str().unwrap()).unwrap();
+        let mut reader2 = open_fastq(outputs[1].to_str().unwrap()).unwrap();
         for _ in 0..4 {
-            let r1 = parse_read(&mut reader1).unwrap();
-            let r2 = parse_read(&mut reader2).unwrap();
+            let r1 = parse_read(&mut reader1).unwrap().unwrap();
+            let r2 = parse_read(&mut reader2).unwrap().unwrap();
             assert_eq!(parse_header(&r1.header).unwrap(),
                        parse_header(&r2.header).unwrap());
         }
@@ -0,0 +1,123 @@
+use fastq_pair::{create_io, Config, KeySet, Keyed, Mate, Output, PartialRead};
+use super::iter_both;
+use super::Result;
+
+/// Pair two FASTQ files that are expected to already be in the same order.
+/// Records are compared in step and written straight out while their keys
+/// match, holding nothing in memory, until the first record whose mate
+/// isn't alongside it. From there on pairing falls back to "iter_both",
+/// starting with the two mismatched records. As there, keys paired are
+/// only remembered with `Config::remember_paired`, so by default a pair
+/// repeated while the inputs are in order is paired again.
+pub fn pair_fastqs(r1_path: &str, r2_path: &str, config: &Config, max_memory: Option<u64>) -> Result<Output> {
+    let mut io = create_io(r1_path, r2_path, config)?;
+    let mut paired = KeySet::default();
+    let unpaired = loop {
+        let record1 = io.in_read1.next_record()?;
+        let record2 = io.in_read2.next_record()?;
+        match (record1, record2) {
+            (Some(Keyed { key: key1, read: read1, .. }), Some(Keyed { key: key2, read: read2, .. })) if key1 == key2 => {
+                if io.out.remember_paired && paired.contains(&key1) {
+                    // Both mates repeat a pair; neither copy was waiting
+                    io.out.duplicate(&key1, &read1, Mate::R1, false)?;
+                    io.out.duplicate(&key2, &read2, Mate::R2, false)?;
+                } else {
+                    io.out.write_pair(&key1, &read1, &read2)?;
+                    if io.out.remember_paired {
+                        paired.insert(&key1);
+                    }
+                }
+            }
+            (Some(Keyed { key: key1, read: read1, .. }), Some(Keyed { key: key2, read: read2, .. })) => {
+                break [Some((key1, PartialRead::from(read1))), Some((key2, PartialRead::from(read2)))];
+            }
+            // One input ran out first, leaving the rest of the other unpaired
+            (record1, record2) => {
+                let owned = |record: Option<Keyed>| record.map(|Keyed { key, read, .. }| (key, PartialRead::from(read)));
+                break [owned(record1), owned(record2)];
+            }
+        }
+    };
+    iter_both::pair_rest(io, max_memory, unpaired, paired)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{shuffled_inputs, sorted_records};
+    use fastq_pair::{Counts, OnDuplicate, OutputNames};
+    use std::fs;
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_paired_inputs() {
+        let tmpdir = tempdir().unwrap();
+        let r1_path = tmpdir.path().join("ncbi_1_paired.fastq");
+        let r2_path = tmpdir.path().join("ncbi_2_paired.fastq");
+        fs::copy("data/ncbi_1_paired.fastq", &r1_path).unwrap();
+        fs::copy("data/ncbi_2_paired.fastq", &r2_path).unwrap();
+        let output = pair_fastqs(r1_path.to_str().unwrap(), r2_path.to_str().unwrap(), &Config::default(), None).unwrap();
+        assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 0, r2_singletons: 0, duplicates: 0 });
+        assert_eq!(fs::read(&output.r1_out_path).unwrap(), fs::read(&r1_path).unwrap());
+        assert_eq!(fs::read(output.r2_out_path.unwrap()).unwrap(), fs::read(&r2_path).unwrap());
+        // Read 2 runs out first, after three pairs
+        let fastq = fs::read_to_string(&r2_path).unwrap();
+        let lines: Vec<&str> = fastq.lines().collect();
+        fs::write(&r2_path, lines[..12].join("\n") + "\n").unwrap();
+        let config = Config { outputs: OutputNames { force: true, ..OutputNames::default() }, ..Config::default() };
+        let output = pair_fastqs(r1_path.to_str().unwrap(), r2_path.to_str().unwrap(), &config, None).unwrap();
+        assert_eq!(output.stats.counts, Counts { pairs: 3, r1_singletons: 1, r2_singletons: 0, duplicates: 0 });
+        let r1_fastq = fs::read_to_string(&r1_path).unwrap();
+        let last: Vec<&str> = r1_fastq.lines().skip(12).collect();
+        assert_eq!(sorted_records(output.singleton_path.as_deref()), vec![last.join("\n") + "\n"]);
+    }
+
+    #[test]
+    fn test_fallback() {
+        let tmpdir = tempdir().unwrap();
+        let (r1_path, r2_path) = shuffled_inputs(tmpdir.path());
+        let expected = iter_both::pair_fastqs(&r1_path, &r2_path, &Config::default(), None).unwrap();
+        let config = Config { outputs: OutputNames { force: true, ..OutputNames::default() }, ..Config::default() };
+        let expected = [sorted_records(Some(&expected.r1_out_path)), sorted_records(expected.singleton_path.as_deref())];
+        let output = pair_fastqs(&r1_path, &r2_path, &config, None).unwrap();
+        assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 0 });
+        assert_eq!([sorted_records(Some(&output.r1_out_path)), sorted_records(output.singleton_path.as_deref())], expected);
+    }
+
+    #[test]
+    fn test_in_order_duplicates() {
+        let tmpdir = tempdir().unwrap();
+        let mut paths = Vec::new();
+        // The first record repeated straight after itself, in one or both
+        // mates, or at the end of both
+        for (mate, repeat) in &[(1, "next"), (2, "next"), (2, "none"), (1, "end"), (2, "end")] {
+            let fastq = fs::read_to_string(format!("data/ncbi_{}_paired.fastq", mate)).unwrap();
+            let lines: Vec<&str> = fastq.lines().collect();
+            let first = lines[..4].join("\n") + "\n";
+            let path = tmpdir.path().join(format!("{}_{}.fastq", mate, repeat));
+            let repeated = match *repeat {
+                "next" => first + &fastq,
+                "end" => fastq.clone() + &first,
+                _ => fastq.clone(),
+            };
+            fs::write(&path, repeated).unwrap();
+            paths.push(path.to_str().unwrap().to_string());
+        }
+        let policy = |on_duplicate| Config { on_duplicate, remember_paired: true,
+                                             outputs: OutputNames { force: true, ..OutputNames::default() }, ..Config::default() };
+        for (r1_path, r2_path) in &[(&paths[0], &paths[1]), (&paths[0], &paths[2]), (&paths[3], &paths[4])] {
+            let err = pair_fastqs(r1_path, r2_path, &policy(OnDuplicate::Error), None).err().unwrap();
+            assert!(err.to_string().contains("appears more than once"), "{}", err);
+        }
+        let output = pair_fastqs(&paths[0], &paths[1], &policy(OnDuplicate::Singletons), None).unwrap();
+        assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 1, r2_singletons: 1, duplicates: 2 });
+        let output = pair_fastqs(&paths[0], &paths[2], &policy(OnDuplicate::First), None).unwrap();
+        assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 0, r2_singletons: 0, duplicates: 1 });
+        let output = pair_fastqs(&paths[3], &paths[4], &policy(OnDuplicate::First), None).unwrap();
+        assert_eq!(output.stats.counts, Counts { pairs: 4, r1_singletons: 0, r2_singletons: 0, duplicates: 2 });
+        // Without remembering paired keys the repeated pair is paired again
+        let bounded = Config { remember_paired: false, ..policy(OnDuplicate::Error) };
+        let output = pair_fastqs(&paths[3], &paths[4], &bounded, None).unwrap();
+        assert_eq!(output.stats.counts.pairs, 5);
+    }
+}
@@ -0,0 +1,104 @@
+use fastq_pair::{create_io, open_fastq, parse_header, parse_read, Config, IO};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{copy, read_to_string, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Copy the shuffled test inputs into `dir`, returning their paths
+pub fn shuffled_inputs(dir: &Path) -> (String, String) {
+    let r1_path = dir.join("ncbi_1_shuffled.fastq");
+    let r2_path = dir.join("ncbi_2_shuffled.fastq");
+    copy("data/ncbi_1_shuffled.fastq", &r1_path).unwrap();
+    copy("data/ncbi_2_shuffled.fastq", &r2_path).unwrap();
+    (r1_path.to_str().unwrap().to_string(), r2_path.to_str().unwrap().to_string())
+}
+
+/// Write the shuffled test inputs into `dir` gzipped, each as two members
+/// like `cat a.gz b.gz` makes, returning their paths
+pub fn gzipped_inputs(dir: &Path) -> (String, String) {
+    let mut paths = Vec::new();
+    for input in &["ncbi_1_shuffled", "ncbi_2_shuffled"] {
+        let path = dir.join(format!("{}.fastq.gz", input));
+        let fastq = read_to_string(format!("data/{}.fastq", input)).unwrap();
+        let (first, second) = fastq.split_at(fastq.len() / 2);
+        let mut out = File::create(&path).unwrap();
+        for part in &[first, second] {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(part.as_bytes()).unwrap();
+            out.write_all(&encoder.finish().unwrap()).unwrap();
+        }
+        paths.push(path.to_str().unwrap().to_string());
+    }
+    (paths[0].clone(), paths[1].clone())
+}
+
+/// IO over copies of the shuffled test inputs in `dir`, for its writers
+pub fn scratch_io(dir: &Path) -> IO {
+    let (r1_path, r2_path) = shuffled_inputs(dir);
+    create_io(&r1_path, &r2_path, &Config::default()).unwrap()
+}
+
+/// Write the shuffled test inputs into `dir` as one FASTQ with records of
+/// both mates alternating, returning its path
+pub fn interleaved_input(dir: &Path) -> String {
+    let path = dir.join("ncbi_interleaved.fastq");
+    let mut reader1 = open_fastq("data/ncbi_1_shuffled.fastq").unwrap();
+    let mut reader2 = open_fastq("data/ncbi_2_shuffled.fastq").unwrap();
+    let mut out = File::create(&path).unwrap();
+    while let (Some(read1), Some(read2)) = (parse_read(&mut reader1).unwrap(), parse_read(&mut reader2).unwrap()) {
+        write!(out, "{}{}", read1, read2).unwrap();
+    }
+    path.to_str().unwrap().to_string()
+}
+
+/// Write the shuffled test inputs into `dir` with sequences (and quality
+/// scores) wrapped every 40 characters, as FASTA if `fasta` is set
+pub fn wrapped_inputs(dir: &Path, fasta: bool) -> (String, String) {
+    let mut paths = Vec::new();
+    for input in &["ncbi_1_shuffled", "ncbi_2_shuffled"] {
+        let path = dir.join(format!("{}.{}", input, if fasta { "fasta" } else { "fastq" }));
+        let mut reader = open_fastq(&format!("data/{}.fastq", input)).unwrap();
+        let mut out = File::create(&path).unwrap();
+        let wrap = |line: &str| line.trim_end().as_bytes().chunks(40)
+            .map(|chunk| format!("{}\n", std::str::from_utf8(chunk).unwrap()))
+            .collect::<String>();
+        while let Some(read) = parse_read(&mut reader).unwrap() {
+            if fasta {
+                write!(out, ">{}{}", &read.header[1..], wrap(&read.seq)).unwrap();
+            } else {
+                write!(out, "{}{}{}{}", read.header, wrap(&read.seq), read.separator, wrap(&read.qscore)).unwrap();
+            }
+        }
+        paths.push(path.to_str().unwrap().to_string());
+    }
+    (paths[0].clone(), paths[1].clone())
+}
+
+/// Parse every record of a FASTQ into its key, sequence and quality scores
+pub fn records(path: &str) -> Vec<(String, String, String)> {
+    let mut reader = open_fastq(path).unwrap();
+    let mut records = Vec::new();
+    while let Some(read) = parse_read(&mut reader).unwrap() {
+        records.push((parse_header(&read.header).unwrap(),
+                      read.seq.trim_end().to_string(),
+                      read.qscore.trim_end().to_string()));
+    }
+    records
+}
+
+/// The raw text of every record in a FASTQ, sorted so that outputs can be
+/// compared regardless of the order a method wrote them in
+pub fn sorted_records(path: Option<&str>) -> Vec<String> {
+    let path = match path {
+        Some(path) => path,
+        None => return Vec::new(),
+    };
+    let mut reader = open_fastq(path).unwrap();
+    let mut records = Vec::new();
+    while let Some(read) = parse_read(&mut reader).unwrap() {
+        records.push(read.to_string());
+    }
+    records.sort();
+    records
+}
@@ -0,0 +1,107 @@
+//! Checks that two FASTQ or FASTA files are properly paired: record for
+//! record, each read's key matches its mate's, and neither file runs out
+//! before the other. Records are parsed as they're compared, so the first
+//! malformed record stops the check with a `ParseError`.
+
+use crate::{Config, HeaderKeyExtractor, Keyed, Mate, open_mate, Records, Result};
+use std::error::Error;
+use std::fmt;
+
+/// A record without a matching mate, and where it was found
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub r1_path: String,
+    pub r2_path: String,
+    /// 1-based number of the record in both files
+    pub record: u64,
+    /// Key and byte offset of the record in each file, None past its end
+    pub r1: Option<(String, u64)>,
+    pub r2: Option<(String, u64)>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.r1, &self.r2) {
+            (Some((key1, offset1)), Some((key2, offset2))) =>
+                write!(f, "{}: record {} at byte {} has key {}, but its mate in {} at byte {} has key {}",
+                       self.r1_path, self.record, offset1, key1, self.r2_path, offset2, key2),
+            (Some((key, offset)), None) =>
+                write!(f, "{}: record {} at byte {} with key {} has no mate, as {} ends after {} records",
+                       self.r1_path, self.record, offset, key, self.r2_path, self.record - 1),
+            (None, Some((key, offset))) =>
+                write!(f, "{}: record {} at byte {} with key {} has no mate, as {} ends after {} records",
+                       self.r2_path, self.record, offset, key, self.r1_path, self.record - 1),
+            (None, None) => write!(f, "record {} is missing from both {} and {}", self.record, self.r1_path, self.r2_path),
+        }
+    }
+}
+
+impl Error for Mismatch {}
+
+/// Step through both files together, returning the number of pairs once
+/// every record has been matched with its mate. Fails with a `Mismatch` at
+/// the first record that isn't, or a `ParseError` at the first malformed one.
+pub fn verify(r1_path: &str, r2_path: &str, config: &Config) -> Result<u64> {
+    let mut reader1 = open_mate(r1_path, Mate::R1, config)?;
+    let mut reader2 = open_mate(r2_path, Mate::R2, config)?;
+    let key1 = HeaderKeyExtractor::for_input(config, &mut reader1)?;
+    let key2 = HeaderKeyExtractor::for_input(config, &mut reader2)?;
+    let mut records1 = Records::new(reader1, key1);
+    let mut records2 = Records::new(reader2, key2);
+    let mut pairs = 0;
+    loop {
+        let record1 = records1.next_record()?.map(|Keyed { key, offset, .. }| (key, offset));
+        let record2 = records2.next_record()?.map(|Keyed { key, offset, .. }| (key, offset));
+        match (&record1, &record2) {
+            (None, None) => return Ok(pairs),
+            (Some((key1, _)), Some((key2, _))) if key1 == key2 => pairs += 1,
+            _ => {
+                return Err(Mismatch {
+                    r1_path: r1_path.to_string(),
+                    r2_path: r2_path.to_string(),
+                    record: pairs + 1,
+                    r1: record1,
+                    r2: record2,
+                }.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ParseError;
+    use std::fs;
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_verify() {
+        let config = Config::default();
+        assert_eq!(verify("data/ncbi_1_paired.fastq", "data/ncbi_2_paired.fastq", &config).unwrap(), 4);
+        let err = verify("data/ncbi_1_shuffled.fastq", "data/ncbi_2_shuffled.fastq", &config).unwrap_err();
+        let mismatch = err.downcast::<Mismatch>().unwrap();
+        assert_eq!(mismatch.record, 1);
+        assert_eq!(mismatch.r1, Some(("@SRR3380692.3".to_string(), 0)));
+        assert!(mismatch.to_string().starts_with("data/ncbi_1_shuffled.fastq: record 1 at byte 0 has key @SRR3380692.3,"));
+    }
+
+    #[test]
+    fn test_verify_uneven() {
+        let tmpdir = tempdir().unwrap();
+        let fastq = fs::read_to_string("data/ncbi_2_paired.fastq").unwrap();
+        let lines: Vec<&str> = fastq.lines().collect();
+        // One record short
+        let short = tmpdir.path().join("short.fastq");
+        fs::write(&short, lines[..12].join("\n") + "\n").unwrap();
+        let err = verify("data/ncbi_1_paired.fastq", short.to_str().unwrap(), &Config::default()).unwrap_err();
+        let mismatch = err.downcast::<Mismatch>().unwrap();
+        assert_eq!((mismatch.record, &mismatch.r2), (4, &None));
+        assert!(mismatch.to_string().contains("ends after 3 records"));
+        // Cut off part way through the last record
+        let truncated = tmpdir.path().join("truncated.fastq");
+        fs::write(&truncated, &fastq[..fastq.len() - 20]).unwrap();
+        let err = verify("data/ncbi_1_paired.fastq", truncated.to_str().unwrap(), &Config::default()).unwrap_err();
+        assert_eq!(err.downcast::<ParseError>().unwrap().record, Some(4));
+    }
+}